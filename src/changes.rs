@@ -0,0 +1,243 @@
+//! Collecting file system events into the set of files that changed.
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// A kind of file system event that can count as a change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// A file was created.
+    Create,
+    /// A file's contents were written to.
+    Modify,
+    /// A file was deleted.
+    Remove,
+    /// A file was moved or renamed, which counts as removing the old name and creating the new one.
+    Rename,
+}
+
+impl FromStr for WatchEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(WatchEvent::Create),
+            "modify" => Ok(WatchEvent::Modify),
+            "remove" => Ok(WatchEvent::Remove),
+            "rename" => Ok(WatchEvent::Rename),
+            _ => Err(format!(
+                "Unknown event '{s}', expected 'create', 'modify', 'remove' or 'rename'"
+            )),
+        }
+    }
+}
+
+/// What happened to a file, after combining all of its events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// Files that changed, each with the overall effect of the events seen for it.
+#[derive(Clone, Debug, Default)]
+pub struct Changes {
+    files: BTreeMap<PathBuf, ChangeKind>,
+}
+
+impl Changes {
+    /// Add the files affected by `event` if it's one of the `accepted` kinds.
+    pub(crate) fn record(&mut self, event: &Event, accepted: &[WatchEvent]) {
+        let accepts = |kind| accepted.contains(&kind);
+
+        match event.kind {
+            EventKind::Create(_) if accepts(WatchEvent::Create) => {
+                self.add_all(&event.paths, ChangeKind::Created)
+            }
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+                if accepts(WatchEvent::Modify) =>
+            {
+                self.add_all(&event.paths, ChangeKind::Modified)
+            }
+            EventKind::Remove(_) if accepts(WatchEvent::Remove) => {
+                self.add_all(&event.paths, ChangeKind::Removed)
+            }
+            EventKind::Modify(ModifyKind::Name(mode)) if accepts(WatchEvent::Rename) => {
+                match (mode, event.paths.as_slice()) {
+                    (RenameMode::From, paths) => self.add_all(paths, ChangeKind::Removed),
+                    (RenameMode::To, paths) => self.add_all(paths, ChangeKind::Created),
+                    // Always accompanied by separate `From` and `To` events.
+                    (RenameMode::Both, _) => {}
+                    // Some platforms don't say which side of the rename a path is on.
+                    (_, paths) => {
+                        for path in paths {
+                            let kind = if path.exists() {
+                                ChangeKind::Created
+                            } else {
+                                ChangeKind::Removed
+                            };
+                            self.add(path, kind);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_all(&mut self, paths: &[PathBuf], kind: ChangeKind) {
+        for path in paths {
+            self.add(path, kind);
+        }
+    }
+
+    /// Combine `kind` with what is already known about `path`.
+    ///
+    /// Atomic saves write a temporary file and rename it over the original, or move the
+    /// original away first. Combining them this way leaves a single change to the original,
+    /// a modification if it was moved away first, and nothing for the temporary file.
+    pub(crate) fn add(&mut self, path: &Path, kind: ChangeKind) {
+        use ChangeKind::*;
+
+        let Some(previous) = self.files.get(path).copied() else {
+            self.files.insert(path.to_owned(), kind);
+            return;
+        };
+
+        let combined = match (previous, kind) {
+            // The file came and went without anyone seeing it.
+            (Created, Removed) => {
+                self.files.remove(path);
+                return;
+            }
+            (Created, _) => Created,
+            (Removed, Created | Modified) | (Modified, Created | Modified) => Modified,
+            (_, Removed) => Removed,
+        };
+        self.files.insert(path.to_owned(), combined);
+    }
+
+    /// The changes to files `predicate` accepts.
+    pub(crate) fn filter(&self, predicate: impl Fn(&Path) -> bool) -> Changes {
+        let files = self
+            .files
+            .iter()
+            .filter(|(path, _)| predicate(path))
+            .map(|(path, kind)| (path.clone(), *kind))
+            .collect();
+        Changes { files }
+    }
+
+    /// Add every change from `other`, combining them as if the events had been seen here.
+    pub(crate) fn merge(&mut self, other: Changes) {
+        for (path, kind) in other.files {
+            self.add(&path, kind);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The changed files in sorted order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, ChangeKind)> {
+        self.files
+            .iter()
+            .map(|(path, kind)| (path.as_path(), *kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ChangeKind::*;
+
+    fn combine(kinds: &[(&str, ChangeKind)]) -> Vec<(PathBuf, ChangeKind)> {
+        let mut changes = Changes::default();
+        for (path, kind) in kinds {
+            changes.add(Path::new(path), *kind);
+        }
+        changes
+            .iter()
+            .map(|(path, kind)| (path.to_owned(), kind))
+            .collect()
+    }
+
+    fn single(path: &str, kind: ChangeKind) -> Vec<(PathBuf, ChangeKind)> {
+        vec![(PathBuf::from(path), kind)]
+    }
+
+    #[test]
+    fn later_events_combine_with_earlier_ones() {
+        assert_eq!(combine(&[("a", Created), ("a", Removed)]), []);
+        assert_eq!(
+            combine(&[("a", Created), ("a", Modified)]),
+            single("a", Created)
+        );
+        assert_eq!(
+            combine(&[("a", Removed), ("a", Created)]),
+            single("a", Modified)
+        );
+        assert_eq!(
+            combine(&[("a", Modified), ("a", Created)]),
+            single("a", Modified)
+        );
+        assert_eq!(
+            combine(&[("a", Modified), ("a", Removed)]),
+            single("a", Removed)
+        );
+        assert_eq!(
+            combine(&[("a", Removed), ("a", Removed)]),
+            single("a", Removed)
+        );
+    }
+
+    #[test]
+    fn atomic_saves_are_a_single_change() {
+        // Writing a temporary file and renaming it over the original. Nothing says the original
+        // was there before, so it looks created.
+        let renamed_over = combine(&[
+            ("page.html.tmp", Created),
+            ("page.html.tmp", Modified),
+            ("page.html.tmp", Removed),
+            ("page.html", Created),
+        ]);
+        assert_eq!(renamed_over, single("page.html", Created));
+
+        // Moving the original to a backup first, then writing a new file.
+        let backed_up = combine(&[
+            ("page.html", Removed),
+            ("page.html~", Created),
+            ("page.html", Created),
+            ("page.html", Modified),
+            ("page.html~", Removed),
+        ]);
+        assert_eq!(backed_up, single("page.html", Modified));
+    }
+
+    #[test]
+    fn merging_combines_like_adding() {
+        let mut changes = Changes::default();
+        changes.add(Path::new("a"), Created);
+        changes.add(Path::new("b"), Modified);
+        let mut later = Changes::default();
+        later.add(Path::new("a"), Removed);
+        later.add(Path::new("b"), Removed);
+        later.add(Path::new("c"), Created);
+        changes.merge(later);
+
+        let merged: Vec<_> = changes.iter().collect();
+        assert_eq!(
+            merged,
+            [(Path::new("b"), Removed), (Path::new("c"), Created)]
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Running shell commands as actions that can be cancelled part way through.
+
+use crate::{Action, ChangeKind, Changes};
+use std::{
+    fmt::Display,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Environment variables longer than this are left out, since the environment has a size limit.
+/// The full list of changed files is always available on stdin.
+const MAX_ENV_LENGTH: usize = 32 * 1024;
+/// How often a running command checks whether it has been cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a stopped process gets to exit on its own before it is killed.
+const KILL_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a restarted process gets to start listening before it counts as failed.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Put before each line a supervised process logs, to tell it apart from the build output.
+const LOG_PREFIX: &str = "[run]";
+
+/// An action that runs `cmd` with the shell, failing with its output if it doesn't succeed.
+///
+/// The changed files are listed in the `DEVSERVER_CHANGED`, `DEVSERVER_CREATED`,
+/// `DEVSERVER_MODIFIED` and `DEVSERVER_REMOVED` environment variables and on stdin, one per line.
+/// If the build is cancelled the command is stopped along with everything it started.
+pub fn shell_action(cmd: String) -> Action {
+    Box::new(move |changes, cancel| {
+        let to_display = |err: io::Error| {
+            let err: Box<dyn Display> = Box::new(err);
+            err
+        };
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let variables = [
+            ("DEVSERVER_CHANGED", None),
+            ("DEVSERVER_CREATED", Some(ChangeKind::Created)),
+            ("DEVSERVER_MODIFIED", Some(ChangeKind::Modified)),
+            ("DEVSERVER_REMOVED", Some(ChangeKind::Removed)),
+        ];
+        for (name, kind) in variables {
+            let value = list(changes, kind);
+            if value.len() <= MAX_ENV_LENGTH {
+                command.env(name, value);
+            }
+        }
+
+        let mut group = ProcessGroup::spawn(&mut command).map_err(to_display)?;
+        let child = group.child();
+
+        // Input and output are handled on other threads so a command that doesn't read its
+        // input, or writes a lot of output, can't block us.
+        let mut stdin = child.stdin.take().unwrap();
+        let changed = list(changes, None) + "\n";
+        thread::spawn(move || stdin.write_all(changed.as_bytes()));
+        let stdout = read_to_end(child.stdout.take().unwrap());
+        let stderr = read_to_end(child.stderr.take().unwrap());
+
+        let status = loop {
+            if cancel.is_cancelled() {
+                group.stop();
+                return Err(Box::new("Cancelled"));
+            }
+            if let Some(status) = group.child().try_wait().map_err(to_display)? {
+                break status;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        print!("{stdout}");
+        let stderr = stderr.join().unwrap_or_default();
+        print!("{stderr}");
+        if !status.success() {
+            return Err(Box::new(format!("{stdout}{stderr}")));
+        }
+        Ok(())
+    })
+}
+
+// The changed files of the given kind, or all of them, one per line.
+fn list(changes: &Changes, kind: Option<ChangeKind>) -> String {
+    let paths: Vec<_> = changes
+        .iter()
+        .filter(|(_, change)| kind.is_none_or(|kind| kind == *change))
+        .map(|(path, _)| path.to_string_lossy())
+        .collect();
+    paths.join("\n")
+}
+
+/// An action that keeps `cmd` running in the background, such as an API server, and restarts it
+/// whenever it runs. The process is started straight away and its output is logged with a prefix.
+///
+/// If a `port` is given the action waits until the process accepts connections on it,
+/// so the page isn't reloaded before the server is ready.
+pub fn process_action(cmd: String, port: Option<u16>) -> Action {
+    let spawn = move || -> io::Result<ProcessGroup> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut group = ProcessGroup::spawn(&mut command)?;
+        let child = group.child();
+        log_lines(child.stdout.take().unwrap(), false);
+        log_lines(child.stderr.take().unwrap(), true);
+        Ok(group)
+    };
+
+    let process = match spawn() {
+        Ok(group) => Some(group),
+        Err(err) => {
+            println!("Could not start process: {err}");
+            None
+        }
+    };
+    let process = Mutex::new(process);
+
+    Box::new(move |_, cancel| {
+        let to_display = |err: io::Error| {
+            let err: Box<dyn Display> = Box::new(err);
+            err
+        };
+
+        let mut process = process.lock().expect("Poisoned lock");
+        if let Some(mut group) = process.take() {
+            group.stop();
+        }
+        let group = process.insert(spawn().map_err(to_display)?);
+
+        let Some(port) = port else {
+            return Ok(());
+        };
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while TcpStream::connect_timeout(&address, POLL_INTERVAL).is_err() {
+            if cancel.is_cancelled() {
+                return Err(Box::new("Cancelled"));
+            }
+            if let Some(status) = group.child().try_wait().map_err(to_display)? {
+                return Err(Box::new(format!("Process exited with {status}")));
+            }
+            if Instant::now() > deadline {
+                return Err(Box::new(format!("Process is not listening on port {port}")));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    })
+}
+
+// Passes a process's output on line by line as it arrives.
+fn log_lines(stream: impl Read + Send + 'static, is_error: bool) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if is_error {
+                eprintln!("{LOG_PREFIX} {line}");
+            } else {
+                println!("{LOG_PREFIX} {line}");
+            }
+        }
+    });
+}
+
+fn read_to_end(mut stream: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stream.read_to_end(&mut output);
+        String::from_utf8_lossy(&output).into_owned()
+    })
+}
+
+/// A child process in a process group of its own, so that it can be stopped along with
+/// anything it has started. Groups still running when devserver is interrupted are stopped too.
+pub(crate) struct ProcessGroup {
+    child: Child,
+}
+
+impl ProcessGroup {
+    pub(crate) fn spawn(command: &mut Command) -> io::Result<Self> {
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(command, 0);
+
+        let child = command.spawn()?;
+        #[cfg(unix)]
+        cleanup::register(child.id());
+        Ok(Self { child })
+    }
+
+    pub(crate) fn child(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Ask the whole group to exit and kill it if it doesn't do so in time.
+    pub(crate) fn stop(&mut self) {
+        #[cfg(unix)]
+        {
+            cleanup::signal(self.child.id(), libc::SIGTERM);
+            let deadline = Instant::now() + KILL_TIMEOUT;
+            while Instant::now() < deadline {
+                if !matches!(self.child.try_wait(), Ok(None)) {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            cleanup::signal(self.child.id(), libc::SIGKILL);
+        }
+
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        cleanup::unregister(self.child.id());
+    }
+}
+
+// Processes in their own group don't receive the Ctrl+C meant for devserver,
+// so they have to be passed on before exiting.
+#[cfg(unix)]
+mod cleanup {
+    use signal_hook::{
+        consts::{SIGHUP, SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+    use std::{
+        sync::{Mutex, Once},
+        thread,
+    };
+
+    static GROUPS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    static HANDLER: Once = Once::new();
+
+    pub(super) fn register(group: u32) {
+        HANDLER.call_once(|| {
+            let Ok(mut signals) = Signals::new([SIGHUP, SIGINT, SIGTERM]) else {
+                return;
+            };
+            thread::spawn(move || {
+                if let Some(received) = signals.forever().next() {
+                    for group in GROUPS.lock().expect("Poisoned lock").iter() {
+                        signal(*group, libc::SIGTERM);
+                    }
+                    std::process::exit(128 + received);
+                }
+            });
+        });
+        GROUPS.lock().expect("Poisoned lock").push(group);
+    }
+
+    pub(super) fn unregister(group: u32) {
+        GROUPS
+            .lock()
+            .expect("Poisoned lock")
+            .retain(|g| *g != group);
+    }
+
+    pub(super) fn signal(group: u32, signal: libc::c_int) {
+        // SAFETY: `killpg` has no memory safety requirements.
+        unsafe {
+            libc::killpg(group as libc::pid_t, signal);
+        }
+    }
+}
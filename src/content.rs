@@ -0,0 +1,78 @@
+//! Response bodies, either generated or streamed from a file.
+
+use crate::Connection;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+
+/// How much of a file is read to tell what kind of document it is.
+const SNIFF_LENGTH: u64 = 1024;
+
+pub(crate) enum Content {
+    Memory(Vec<u8>),
+    /// Sent straight from disk, so files of any size only take a small buffer.
+    File {
+        file: File,
+        length: u64,
+    },
+}
+
+impl Content {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        Ok(Content::File { file, length })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Content::Memory(contents) => contents.len() as u64,
+            Content::File { length, .. } => *length,
+        }
+    }
+
+    /// The start of the content, enough to recognize the kind of document.
+    pub(crate) fn start(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Content::Memory(contents) => {
+                let end = contents.len().min(SNIFF_LENGTH as usize);
+                Ok(contents[..end].to_vec())
+            }
+            Content::File { file, .. } => {
+                let mut start = Vec::new();
+                file.seek(SeekFrom::Start(0))?;
+                file.take(SNIFF_LENGTH).read_to_end(&mut start)?;
+                Ok(start)
+            }
+        }
+    }
+
+    /// All of the content, for changes that need the whole document such as injecting a script.
+    pub(crate) fn into_memory(self) -> io::Result<Vec<u8>> {
+        match self {
+            Content::Memory(contents) => Ok(contents),
+            Content::File { mut file, length } => {
+                let mut contents = Vec::with_capacity(length as usize);
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut contents)?;
+                Ok(contents)
+            }
+        }
+    }
+
+    pub(crate) fn write_range<T: Connection>(
+        &mut self,
+        stream: &mut T,
+        range: Range<u64>,
+    ) -> io::Result<()> {
+        match self {
+            Content::Memory(contents) => {
+                stream.write_all(&contents[range.start as usize..range.end as usize])
+            }
+            Content::File { file, .. } => stream.send_file(file, range),
+        }
+    }
+}
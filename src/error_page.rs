@@ -0,0 +1,119 @@
+//! Built-in HTML pages for error responses.
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// How many similarly named files a missing file's page suggests.
+const MAX_CANDIDATES: usize = 10;
+
+/// A page with a title like `404 Not Found` and a message, which must already be escaped.
+pub(crate) fn render(title: &str, message: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n\
+         <body>\n<h1>{title}</h1>\n{message}\n</body>\n</html>\n"
+    )
+}
+
+/// The page for a missing file, which links to files with similar names near where it was expected.
+/// `url_path` is the path as requested and `file` where it was looked for under `root`.
+pub(crate) fn not_found(root: &Path, url_path: &str, file: &Path) -> String {
+    let mut message = format!("<p>Could not find <code>{}</code>.</p>", escape(url_path));
+
+    let candidates = candidates(root, file);
+    if !candidates.is_empty() {
+        message.push_str("\n<p>Did you mean one of these?</p>\n<ul>\n");
+        for (url, name) in candidates {
+            message.push_str(&format!(
+                "<li><a href=\"{}\">{}</a></li>\n",
+                escape(&url),
+                escape(&name)
+            ));
+        }
+        message.push_str("</ul>");
+    }
+    render("404 Not Found", &message)
+}
+
+// The URLs and names of the entries in the closest existing folder, most similar names first.
+fn candidates(root: &Path, file: &Path) -> Vec<(String, String)> {
+    // Only folders inside `root` are ever listed, which a parent folder component could get around.
+    if file
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Vec::new();
+    }
+    let Some(mut folder) = file.parent().map(PathBuf::from) else {
+        return Vec::new();
+    };
+    while !folder.is_dir() {
+        if !folder.pop() || !folder.starts_with(root) {
+            return Vec::new();
+        }
+    }
+    let Ok(entries) = fs::read_dir(&folder) else {
+        return Vec::new();
+    };
+
+    let wanted = file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut names: Vec<_> = entries
+        .flatten()
+        .map(|entry| {
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            name
+        })
+        .filter(|name| !name.starts_with('.'))
+        .map(|name| (distance(&wanted, &name.to_lowercase()), name))
+        .collect();
+    names.sort();
+
+    let base = folder.strip_prefix(root).unwrap_or(Path::new(""));
+    names
+        .into_iter()
+        .take(MAX_CANDIDATES)
+        .map(|(_, name)| {
+            let path = base.join(&name);
+            let url = format!("/{}", path.to_string_lossy().replace('\\', "/"));
+            (url.replace(' ', "%20"), name)
+        })
+        .collect()
+}
+
+// The number of characters that have to be inserted, removed or replaced to turn one name into the other.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a != *b);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Escape text so that it can be placed in HTML, including attribute values.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,196 @@
+//! Deciding which files and folders are worth watching.
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder, Glob},
+    Match,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// Files that list patterns to ignore, as understood by git and other tools.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+pub(crate) struct Filter {
+    root: PathBuf,
+    // Patterns given explicitly, relative to the watched folder.
+    patterns: Gitignore,
+    // Whether ignore files are honoured at all.
+    use_ignore_files: bool,
+    // Ignore files found so far, keyed by the folder they apply to.
+    ignore_files: BTreeMap<PathBuf, Vec<Gitignore>>,
+}
+
+impl Filter {
+    pub(crate) fn new(root: &Path, patterns: &[String], use_ignore_files: bool) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            if let Err(err) = builder.add_line(None, pattern) {
+                println!("WARNING: Invalid ignore pattern [ {pattern} ]: {err}");
+            }
+        }
+
+        let mut filter = Self {
+            root: root.to_owned(),
+            patterns: builder.build().unwrap_or_else(|_| Gitignore::empty()),
+            use_ignore_files,
+            ignore_files: BTreeMap::new(),
+        };
+
+        // Ignore files in parent folders apply too, up to the root of the repository.
+        for folder in root.ancestors() {
+            filter.load_ignore_files(folder);
+            if folder.join(".git").exists() {
+                break;
+            }
+        }
+        filter
+    }
+
+    /// The folder patterns are relative to.
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// (Re)read the ignore files in `folder`.
+    pub(crate) fn load_ignore_files(&mut self, folder: &Path) {
+        if !self.use_ignore_files {
+            return;
+        }
+
+        let matchers: Vec<_> = IGNORE_FILES
+            .iter()
+            .map(|name| folder.join(name))
+            .filter(|path| path.is_file())
+            .map(|path| Gitignore::new(path).0)
+            .collect();
+
+        if matchers.is_empty() {
+            self.ignore_files.remove(folder);
+        } else {
+            self.ignore_files.insert(folder.to_owned(), matchers);
+        }
+    }
+
+    /// Whether `path` is one of the ignore files this filter reads.
+    pub(crate) fn is_ignore_file(&self, path: &Path) -> bool {
+        self.use_ignore_files
+            && path
+                .file_name()
+                .is_some_and(|name| IGNORE_FILES.iter().any(|file| name == *file))
+    }
+
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        if relative.as_os_str().is_empty() {
+            return false;
+        }
+
+        // Git's own bookkeeping never affects the page.
+        if relative
+            .components()
+            .any(|component| component.as_os_str() == ".git")
+        {
+            return true;
+        }
+
+        if self
+            .patterns
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return true;
+        }
+
+        // The closest ignore file with an opinion wins, and within a folder `.ignore` beats `.gitignore`.
+        for folder in path.ancestors().skip(1) {
+            for matcher in self.ignore_files.get(folder).into_iter().flatten().rev() {
+                let matched = self.matched_below_root(matcher, folder, path, is_dir);
+                if matched.is_ignore() {
+                    return true;
+                }
+                if matched.is_whitelist() {
+                    return false;
+                }
+            }
+        }
+        false
+    }
+
+    // Like `matched_path_or_any_parents` for the ignore file in `folder`, but stops short of the
+    // watched root, so that a rule above it that matches the root itself, such as `dist/` in the
+    // repository's `.gitignore` when serving `dist`, doesn't hide everything being watched.
+    fn matched_below_root<'a>(
+        &self,
+        matcher: &'a Gitignore,
+        folder: &Path,
+        path: &Path,
+        is_dir: bool,
+    ) -> Match<&'a Glob> {
+        let stop = if folder.starts_with(&self.root) {
+            folder
+        } else {
+            &self.root
+        };
+        let mut is_dir = is_dir;
+        for candidate in path.ancestors().take_while(|candidate| *candidate != stop) {
+            let matched = matcher.matched(candidate, is_dir);
+            if !matched.is_none() {
+                return matched;
+            }
+            is_dir = true;
+        }
+        Match::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    // A fresh folder laid out like a repository with `dist/` ignored, served from `dist`.
+    fn repository(name: &str) -> PathBuf {
+        let repository = std::env::temp_dir().join(format!("devserver-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&repository);
+        fs::create_dir_all(repository.join(".git")).unwrap();
+        fs::create_dir_all(repository.join("dist/assets")).unwrap();
+        fs::write(repository.join(".gitignore"), "dist/\n*.log\n").unwrap();
+        repository
+    }
+
+    #[test]
+    fn ignored_root_is_still_watched() {
+        let repository = repository("ignored-root");
+        let root = repository.join("dist");
+        let filter = Filter::new(&root, &[], true);
+
+        assert!(!filter.is_ignored(&root, true));
+        assert!(!filter.is_ignored(&root.join("index.html"), false));
+        assert!(!filter.is_ignored(&root.join("assets"), true));
+        assert!(!filter.is_ignored(&root.join("assets/app.js"), false));
+        // Rules from above the root still apply to what is below it.
+        assert!(filter.is_ignored(&root.join("debug.log"), false));
+        assert!(filter.is_ignored(&root.join(".git/HEAD"), false));
+
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn ignore_files_apply_to_their_folder() {
+        let repository = repository("nested");
+        let root = repository.join("dist");
+        fs::write(root.join(".gitignore"), "assets/\n").unwrap();
+        let filter = Filter::new(&root, &["*.tmp".to_owned()], true);
+
+        assert!(filter.is_ignored(&root.join("assets"), true));
+        assert!(filter.is_ignored(&root.join("assets/app.js"), false));
+        assert!(filter.is_ignored(&root.join("page.tmp"), false));
+        assert!(!filter.is_ignored(&root.join("page.html"), false));
+
+        fs::remove_dir_all(repository).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+//! Insertion of the reload script into HTML documents.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::str::FromStr;
+
+const RELOAD_SCRIPT: &[u8] = include_bytes!("reload.html");
+
+/// Where the reload script is placed in an HTML document.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Injection {
+    /// Just before `</body>`, once the rest of the page has been parsed.
+    #[default]
+    Body,
+    /// Just before `</head>`, so reloading works even if the page never finishes loading.
+    Head,
+}
+
+impl FromStr for Injection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "body" => Ok(Injection::Body),
+            "head" => Ok(Injection::Head),
+            _ => Err(format!(
+                "Unknown injection point '{s}', expected 'body' or 'head'"
+            )),
+        }
+    }
+}
+
+/// Changes whenever the injected script or where it goes does, so cached pages can be told apart.
+pub(crate) fn version(injection: Injection) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    RELOAD_SCRIPT.hash(&mut hasher);
+    injection.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether responses with this media type are documents the reload script can be injected into.
+pub(crate) fn is_html(content_type: &str) -> bool {
+    content_type.starts_with("text/html") || content_type.starts_with("application/xhtml+xml")
+}
+
+/// Insert the reload script into `document`.
+///
+/// If the preferred tag is missing the other one is tried, then `</html>`.
+/// Documents without any of them get the script appended to the end.
+pub(crate) fn inject(document: &mut Vec<u8>, injection: Injection) {
+    let closing_head = || find(document, b"</head", false);
+    let closing_body = || find(document, b"</body", true);
+
+    let index = match injection {
+        Injection::Body => closing_body().or_else(closing_head),
+        Injection::Head => closing_head().or_else(closing_body),
+    }
+    .or_else(|| find(document, b"</html", true))
+    .unwrap_or(document.len());
+
+    document.splice(index..index, RELOAD_SCRIPT.iter().copied());
+}
+
+// Case insensitive search for `needle`, returning the index of the first or last occurrence.
+fn find(haystack: &[u8], needle: &[u8], last: bool) -> Option<usize> {
+    let mut matches = haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| window.eq_ignore_ascii_case(needle))
+        .map(|(index, _)| index);
+
+    if last {
+        matches.next_back()
+    } else {
+        matches.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where the script ended up in `document`, checking that nothing else changed.
+    fn injected_at(document: &str, injection: Injection) -> usize {
+        let mut injected = document.as_bytes().to_vec();
+        inject(&mut injected, injection);
+        assert_eq!(injected.len(), document.len() + RELOAD_SCRIPT.len());
+
+        let index = injected
+            .windows(RELOAD_SCRIPT.len())
+            .position(|window| window == RELOAD_SCRIPT)
+            .unwrap();
+        assert_eq!(&injected[..index], &document.as_bytes()[..index]);
+        assert_eq!(
+            &injected[index + RELOAD_SCRIPT.len()..],
+            &document.as_bytes()[index..]
+        );
+        index
+    }
+
+    #[test]
+    fn body_goes_before_the_last_closing_body_tag() {
+        let document = "<html><head></head><body><pre></body></pre></body></html>";
+        assert_eq!(
+            injected_at(document, Injection::Body),
+            document.rfind("</body>").unwrap()
+        );
+    }
+
+    #[test]
+    fn head_goes_before_the_first_closing_head_tag() {
+        let document = "<html><head><title></title></head><body><pre></head></pre></body></html>";
+        assert_eq!(
+            injected_at(document, Injection::Head),
+            document.find("</head>").unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_tags_fall_back_in_order() {
+        let no_body = "<html><head></head><p>text</html>";
+        assert_eq!(
+            injected_at(no_body, Injection::Body),
+            no_body.find("</head>").unwrap()
+        );
+
+        let no_head = "<html><body><p>text</body></html>";
+        assert_eq!(
+            injected_at(no_head, Injection::Head),
+            no_head.find("</body>").unwrap()
+        );
+
+        let only_html = "<html><p>text</html>\n";
+        for injection in [Injection::Body, Injection::Head] {
+            assert_eq!(
+                injected_at(only_html, injection),
+                only_html.find("</html>").unwrap()
+            );
+        }
+
+        let fragment = "<p>text</p>";
+        assert_eq!(injected_at(fragment, Injection::Body), fragment.len());
+        assert_eq!(injected_at("", Injection::Head), 0);
+    }
+
+    #[test]
+    fn tags_are_found_in_any_case() {
+        let document = "<HTML><HEAD></HEAD><BODY></Body></HTML>";
+        assert_eq!(
+            injected_at(document, Injection::Body),
+            document.find("</Body>").unwrap()
+        );
+        assert_eq!(
+            injected_at(document, Injection::Head),
+            document.find("</HEAD>").unwrap()
+        );
+    }
+}
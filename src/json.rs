@@ -0,0 +1,28 @@
+//! Just enough JSON encoding for the messages and listings devserver produces.
+
+use std::fmt::Write;
+
+/// Encode `value` as a quoted JSON string.
+pub(crate) fn string(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len() + 2);
+    encoded.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => encoded.push_str("\\\""),
+            '\\' => encoded.push_str("\\\\"),
+            '\n' => encoded.push_str("\\n"),
+            '\r' => encoded.push_str("\\r"),
+            '\t' => encoded.push_str("\\t"),
+            c if c.is_control() => write!(encoded, "\\u{:04x}", c as u32).unwrap(),
+            c => encoded.push(c),
+        }
+    }
+    encoded.push('"');
+    encoded
+}
+
+/// Encode a list of strings as a JSON array.
+pub(crate) fn string_array<'a>(values: impl IntoIterator<Item = &'a str>) -> String {
+    let values: Vec<_> = values.into_iter().map(string).collect();
+    format!("[{}]", values.join(","))
+}
@@ -1,28 +1,59 @@
-/// A local host only for serving static files.
-/// Simple and easy, but not robust or tested.
-use native_tls::{Identity, Protocol, TlsAcceptor};
+//! A local host only for serving static files.
+//! Simple and easy, but not robust or tested.
+
+use content::Content;
+use httpdate::HttpDate;
+use message::{relay_body, Body};
+use native_tls::{Identity, Protocol, TlsAcceptor, TlsStream};
+use range::Ranges;
+use request::Request;
+
+pub use changes::{ChangeKind, Changes, WatchEvent};
+pub use command::{process_action, shell_action};
+pub use inject::Injection;
+pub use proxy::Proxy;
 use std::sync::Arc;
+pub use watch::CancelToken;
 
 use std::ffi::OsStr;
 use std::fmt::Display;
 use std::fs;
-use std::io::BufRead;
-use std::io::{Read, Write};
-use std::net::{IpAddr, TcpListener};
-use std::path::Path;
-use std::str;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
+mod changes;
+mod command;
+mod content;
+mod error_page;
+mod filter;
+mod inject;
+mod json;
+mod listing;
+mod message;
 mod mime;
+mod proxy;
+mod range;
 mod reload;
+mod request;
+mod watch;
 
-pub type Action = Box<dyn Fn() -> Result<(), Box<dyn Display>> + Send>;
+/// Run in response to changes, which are passed in.
+/// Long running actions should check the token and return early once it's cancelled.
+pub type Action = Box<dyn Fn(&Changes, &CancelToken) -> Result<(), Box<dyn Display>> + Send>;
 
-pub fn read_header<T: Read + Write>(stream: &mut T) -> Vec<u8> {
+pub fn read_header<T: BufRead>(stream: &mut T) -> Vec<u8> {
     let mut buffer = Vec::new();
-    let mut reader = std::io::BufReader::new(stream);
+    // Read a line at a time so nothing after the header, like a request body, is consumed.
     loop {
-        reader.read_until(b'\n', &mut buffer).unwrap();
+        // Stop if the client hangs up before finishing the header.
+        if stream.read_until(b'\n', &mut buffer).unwrap_or(0) == 0 {
+            break;
+        }
         // Read until end of header.
         if buffer.ends_with(b"\r\n\r\n") {
             break;
@@ -31,105 +62,645 @@ pub fn read_header<T: Read + Write>(stream: &mut T) -> Vec<u8> {
     buffer
 }
 
-fn handle_client<T: Read + Write>(
-    mut stream: T,
-    root_path: impl AsRef<Path>,
-    reload: bool,
-    headers: &str,
-) {
-    let buffer = read_header(&mut stream);
-    let request_string = str::from_utf8(&buffer).unwrap();
+/// The methods files can be requested with.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// How long a connection is kept open waiting for another request.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells the client that the connection closes once the response has been sent.
+const CONNECTION_CLOSE: &str = "\r\nConnection: close";
+
+/// A connection to a client, either plain or over TLS.
+pub(crate) trait Connection: Read + Write {
+    /// The underlying socket, used to configure timeouts.
+    fn socket(&self) -> &TcpStream;
+
+    /// Send part of a file, through a small buffer unless the connection has a faster way.
+    fn send_file(&mut self, file: &mut File, range: Range<u64>) -> io::Result<()> {
+        file.seek(SeekFrom::Start(range.start))?;
+        let length = range.end - range.start;
+        if io::copy(&mut file.take(length), self)? < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+impl Connection for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+
+    // The kernel copies the file to the socket itself, without going through a buffer here.
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &mut File, range: Range<u64>) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        // The most Linux sends in a single call.
+        const MAX_SEND: u64 = 0x7fff_f000;
+
+        let mut offset = range.start as libc::off_t;
+        let end = range.end as libc::off_t;
+        while offset < end {
+            let count = (end - offset).min(MAX_SEND as libc::off_t) as usize;
+            // SAFETY: Both descriptors stay open for the call and `offset` points to a valid `off_t`.
+            let sent =
+                unsafe { libc::sendfile(self.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+            match sent {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                // The file got shorter since its length was taken.
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Connection + ?Sized> Connection for &mut T {
+    fn socket(&self) -> &TcpStream {
+        (**self).socket()
+    }
+
+    fn send_file(&mut self, file: &mut File, range: Range<u64>) -> io::Result<()> {
+        (**self).send_file(file, range)
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        self.get_ref()
+    }
+}
 
-    if request_string.is_empty() {
-        return;
+/// A connection read through one buffer for as long as it's open, so that anything a client
+/// sends ahead, like its next request, is still there for the next read.
+pub(crate) struct Buffered<T>(BufReader<T>);
+
+impl<T: Read> Read for Buffered<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<T: Read> BufRead for Buffered<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.0.consume(amount)
+    }
+}
+
+impl<T: Read + Write> Write for Buffered<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+impl<T: Connection> Connection for Buffered<T> {
+    fn socket(&self) -> &TcpStream {
+        self.0.get_ref().socket()
+    }
+
+    fn send_file(&mut self, file: &mut File, range: Range<u64>) -> io::Result<()> {
+        self.0.get_mut().send_file(file, range)
     }
+}
+
+/// Settings that control how files are served.
+pub struct Options {
+    /// The folder files are served from.
+    pub root_path: PathBuf,
+    /// Inject the reload script into HTML documents.
+    pub reload: bool,
+    /// Where the reload script is placed in HTML documents.
+    pub injection: Injection,
+    /// Extra headers added to every response, each preceded by a line break.
+    pub headers: String,
+    /// Files served for a folder, in order of preference, like `index.html`.
+    pub index_files: Vec<String>,
+    /// Extensions tried in order for files requested without one, so `/about` can serve `about.html`.
+    pub extensions: Vec<String>,
+    /// Redirect pages to their URL without an extension, and index pages to their folder.
+    pub clean_urls: bool,
+    /// List the contents of folders that don't have an `index.html`.
+    pub listing: bool,
+    /// Served, relative to `root_path`, for pages that don't exist, so client side routing works.
+    pub fallback: Option<PathBuf>,
+    /// Paths forwarded to other servers instead of being served from `root_path`.
+    /// The first matching proxy is used.
+    pub proxies: Vec<Proxy>,
+}
+
+/// Actions to run when something changes in part of the file system.
+pub struct WatchRule {
+    /// A file, a folder, or a glob such as `shaders/**/*.glsl`.
+    pub path: PathBuf,
+    /// Run in order when a matching file changes, stopping at the first error.
+    pub actions: Vec<Action>,
+}
+
+/// Settings that control what counts as a change and what happens when one occurs.
+pub struct WatchOptions {
+    /// What to watch. A change to anything they match reloads the page.
+    pub rules: Vec<WatchRule>,
+    /// The kinds of file system events that count as a change.
+    pub events: Vec<WatchEvent>,
+    /// Changes are collected until none have happened for this long, then handled together.
+    pub debounce: Duration,
+    /// Gitignore style patterns for files and folders that never count as a change.
+    pub ignore: Vec<String>,
+    /// Also ignore whatever `.gitignore` and `.ignore` files in and above the watched folder list.
+    pub ignore_files: bool,
+    /// Files and folders written by the actions. Changes to them reload the page without
+    /// running the actions again. Declaring them also lets devserver tell edits apart from
+    /// build output, so an edit made during a build cancels it and starts it again.
+    pub outputs: Vec<PathBuf>,
+}
+
+fn handle_client<T: Connection>(stream: T, options: &Options, hub: &reload::Hub) {
+    // Browsers reuse connections for the other files on a page, and may send several requests
+    // before reading the responses. Each is answered in turn until the connection goes quiet.
+    let mut stream = Buffered(BufReader::new(stream));
+    loop {
+        if stream
+            .socket()
+            .set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))
+            .is_err()
+        {
+            return;
+        }
+        let buffer = read_header(&mut stream);
+        if buffer.is_empty() {
+            return;
+        }
+
+        let Some(request) = Request::parse(&buffer) else {
+            let page = error_page::render("400 Bad Request", "<p>The request is malformed.</p>");
+            send_error(
+                stream,
+                false,
+                "400 BAD REQUEST",
+                CONNECTION_CLOSE,
+                page.into_bytes(),
+                options,
+            );
+            return;
+        };
 
-    // Split the request into different parts.
-    let mut parts = request_string.split(' ');
+        let is_persistent = request.is_persistent();
+        if !handle_request(&mut stream, &request, options, hub) || !is_persistent {
+            return;
+        }
+    }
+}
 
-    let _method = parts.next().unwrap().trim();
-    let mut path = parts.next().unwrap().trim();
-    let _http_version = parts.next().unwrap().trim();
+// Answers a single request. Returns whether the connection can be used for another one.
+fn handle_request<T: Connection>(
+    stream: &mut Buffered<T>,
+    request: &Request,
+    options: &Options,
+    hub: &reload::Hub,
+) -> bool {
+    // The reload script connects back to the same port and is handed over to the reload subsystem.
+    if request.path() == reload::RELOAD_PATH {
+        reload::accept(stream, request, hub);
+        return false;
+    }
 
-    // Trim parameters from URL
-    if let Some(parameters_index) = path.find('?') {
-        path = &path[..parameters_index];
+    let path = request.path();
+    if let Some(proxy) = options.proxies.iter().find(|proxy| proxy.matches(path)) {
+        return proxy::forward(stream, request, proxy);
     }
 
-    // Replace white space characters with proper whitespace and remove any paths that refer to the parent.
-    let path = path.replace("../", "").replace("%20", " ");
+    // Nothing here uses a request body, but it has to be read to get to the next request.
+    if relay_body(&mut *stream, io::sink(), Body::of_request(request)).is_err() {
+        return false;
+    }
+
+    let connection = connection_header(request);
+
+    // Files can only be read, anything else is up to the other subsystems.
+    let is_head = request.method == "HEAD";
+    match request.method.as_str() {
+        "GET" | "HEAD" => {}
+        "OPTIONS" => {
+            let response = format!(
+                "HTTP/1.1 204 NO CONTENT\r\nAllow: {ALLOWED_METHODS}{}{connection}\r\n\r\n",
+                options.headers
+            );
+            return stream
+                .write_all(response.as_bytes())
+                .and_then(|_| stream.flush())
+                .is_ok();
+        }
+        method => {
+            let message = format!(
+                "<p>Files can't be requested with <code>{}</code>.</p>",
+                error_page::escape(method)
+            );
+            let page = error_page::render("405 Method Not Allowed", &message);
+            // Like a malformed request, this ends the connection.
+            let headers = format!("\r\nAllow: {ALLOWED_METHODS}{CONNECTION_CLOSE}");
+            send_error(
+                stream,
+                false,
+                "405 METHOD NOT ALLOWED",
+                &headers,
+                page.into_bytes(),
+                options,
+            );
+            return false;
+        }
+    }
+
+    // Replace white space characters with proper whitespace. Paths that refer to a parent folder
+    // could climb out of the served folder, so they're turned away before anything is looked up.
+    let path = request.path().replace("%20", " ");
+    if path.split(['/', '\\']).any(|segment| segment == "..") {
+        let page = error_page::render(
+            "400 Bad Request",
+            "<p>Paths can't refer to a parent folder.</p>",
+        );
+        send_error(
+            stream,
+            is_head,
+            "400 BAD REQUEST",
+            CONNECTION_CLOSE,
+            page.into_bytes(),
+            options,
+        );
+        return false;
+    }
     let path = if path.ends_with('/') {
-        root_path.as_ref().join(Path::new(&format!(
-            "{}{}",
-            path.trim_start_matches('/'),
-            "index.html"
-        )))
+        // The first index file that exists, or the preferred one to report as missing.
+        let folder = options.root_path.join(path.trim_start_matches('/'));
+        let index = options
+            .index_files
+            .iter()
+            .map(|name| folder.join(name))
+            .find(|index| index.is_file());
+        index.unwrap_or_else(|| {
+            folder.join(
+                options
+                    .index_files
+                    .first()
+                    .map_or("index.html", String::as_str),
+            )
+        })
     } else {
-        root_path.as_ref().join(path.trim_matches('/'))
+        options.root_path.join(path.trim_matches('/'))
     };
 
-    let extension = path.extension().and_then(OsStr::to_str);
+    // Relative links in a folder's index page only work if the URL ends with a slash,
+    // so folders are redirected there like on most static hosts.
+    if !request.path().ends_with('/') && path.is_dir() {
+        return redirect(stream, request, &format!("{}/", request.path()), options);
+    }
 
-    let (file_contents, extension) = if extension.is_some() {
-        (fs::read(&path), extension)
-    } else {
-        // If the request has no extension look first for a matching file without an extension
-        if let Ok(file_contents) = fs::read(&path) {
-            println!("WARNING: Serving file without extension: [ {} ] with media type 'application/octet-stream'", &path.to_str().unwrap());
-            (Ok(file_contents), None)
+    // With clean URLs pages are only linked without their extension, and index pages by their folder.
+    if options.clean_urls && path.is_file() {
+        let url_path = request.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        let clean = if options.index_files.iter().any(|index| index == name) {
+            url_path.strip_suffix(name)
         } else {
-            // If no file without an extension is found see if there's a file with a ".html" extension
-            // This enables "pretty URLs" without a trailing `/` like: `example.com/blog-post`
-            let file = fs::read(path.with_extension("html"));
-            (file, Some("html"))
+            let extension = path.extension().and_then(OsStr::to_str);
+            extension
+                .filter(|extension| options.extensions.iter().any(|e| e == extension))
+                .and_then(|extension| url_path.strip_suffix(&format!(".{extension}")))
+        };
+        if let Some(clean) = clean {
+            return redirect(stream, request, clean, options);
         }
-    };
+    }
+
+    let mut extension = path.extension().and_then(OsStr::to_str);
+    let mut file = path.is_file().then(|| path.clone());
+    if file.is_none() && extension.is_none() {
+        // If no file without an extension is found see if there's one with a fallback extension.
+        // This enables "pretty URLs" without a trailing `/` like: `example.com/blog-post`
+        let found = options
+            .extensions
+            .iter()
+            .map(|fallback| (path.with_extension(fallback), fallback))
+            .find(|(candidate, _)| candidate.is_file());
+        if let Some((candidate, fallback)) = found {
+            file = Some(candidate);
+            extension = Some(fallback);
+        }
+    }
 
-    if let Ok(mut file_contents) = file_contents {
-        // Pair the file extension to a media (also known as MIME) type.
-        let content_type = mime::from_extension(extension);
+    // Folders without an index page are listed instead.
+    let folder = path.parent().filter(|folder| {
+        options.listing && file.is_none() && request.path().ends_with('/') && folder.is_dir()
+    });
+    let listing = folder.and_then(|folder| listing::list(folder, request).ok());
 
-        let mut content_length = file_contents.len();
+    // Client side routers handle paths that don't exist as files, so pages navigating to them get
+    // the fallback instead. Missing assets like scripts and images are still reported as missing.
+    let fallback = options.fallback.as_ref().filter(|_| {
+        let is_page = matches!(extension, None | Some("html" | "htm"));
+        file.is_none()
+            && listing.is_none()
+            && request
+                .header("Accept")
+                .is_some_and(|accept| accept.contains("text/html"))
+            && is_page
+    });
+    if let Some(fallback) = fallback {
+        let candidate = options.root_path.join(fallback);
+        if candidate.is_file() {
+            file = Some(candidate);
+            extension = fallback.extension().and_then(OsStr::to_str);
+        }
+    }
 
-        // Prepare to inject code into HTML if reload is enabled.
-        let reload_append = include_bytes!("reload.html");
-        if extension == Some("html") && reload {
-            content_length += reload_append.len();
+    let mut content = match (listing, &file) {
+        (Some((listing, format)), _) => {
+            extension = Some(format);
+            Ok(Content::Memory(listing))
         }
+        (None, Some(file)) => Content::open(file),
+        (None, None) => Err(io::ErrorKind::NotFound.into()),
+    };
+
+    // Pair the file extension to a media (also known as MIME) type.
+    let is_html_document = extension.is_none()
+        && content.as_mut().is_ok_and(|content| {
+            content
+                .start()
+                .is_ok_and(|start| mime::is_html_document(&start))
+        });
+    let content_type = if is_html_document {
+        mime::from_extension(Some("html"))
+    } else {
+        mime::from_extension(extension)
+    };
+    let is_injected = options.reload && inject::is_html(content_type);
 
+    // Generated listings change with the folder, so only files can be cached.
+    let metadata = file.as_ref().and_then(|file| fs::metadata(file).ok());
+    let modified = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.modified().ok());
+    let etag = metadata
+        .as_ref()
+        .map(|metadata| etag(metadata, is_injected, options));
+
+    // Browsers that have the current version already don't need it again. The date of an
+    // injected document doesn't change with the reload script, so only its ETag can tell.
+    let validated_date = modified.filter(|_| !is_injected);
+    let is_fresh = match (
+        request.header("If-None-Match"),
+        request.header("If-Modified-Since"),
+    ) {
+        (Some(if_none_match), _) => etag
+            .as_deref()
+            .is_some_and(|etag| etag_matches(if_none_match, etag)),
+        // Dates only have whole seconds, which `HttpDate` compares by.
+        (None, Some(if_modified_since)) => validated_date.is_some_and(|modified| {
+            if_modified_since
+                .parse::<HttpDate>()
+                .is_ok_and(|since| HttpDate::from(modified) <= since)
+        }),
+        (None, None) => false,
+    };
+    // Browsers check with the server before using a cached file, unless the extra headers say otherwise.
+    let has_cache_control = options.headers.split("\r\n").any(|header| {
+        header
+            .split_once(':')
+            .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("Cache-Control"))
+    });
+    let cache_control = if has_cache_control {
+        ""
+    } else {
+        "\r\nCache-Control: no-cache"
+    };
+    let validators = match (&etag, modified) {
+        (Some(etag), Some(modified)) => format!(
+            "\r\nETag: {etag}\r\nLast-Modified: {}{cache_control}",
+            httpdate::fmt_http_date(modified)
+        ),
+        (Some(etag), None) => format!("\r\nETag: {etag}{cache_control}"),
+        _ => String::new(),
+    };
+    if is_fresh {
         let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-type: {}\r\nContent-Length: {}{}\r\n\r\n",
-            content_type, content_length, headers
+            "HTTP/1.1 304 NOT MODIFIED{}{}{connection}\r\n\r\n",
+            validators, options.headers
         );
+        return stream
+            .write_all(response.as_bytes())
+            .and_then(|_| stream.flush())
+            .is_ok();
+    }
 
-        let mut bytes = response.as_bytes().to_vec();
-        bytes.append(&mut file_contents);
-        stream.write_all(&bytes).unwrap();
+    if let Ok(mut content) = content {
+        if extension.is_none() {
+            println!(
+                "WARNING: Serving file without extension: [ {} ] with media type '{}'",
+                &path.to_str().unwrap(),
+                content_type
+            );
+        }
 
         // Inject code into HTML if reload is enabled
-        if extension == Some("html") && reload {
-            // Insert javascript for reloading
-            stream.write_all(reload_append).unwrap();
+        if is_injected {
+            let Ok(mut document) = content.into_memory() else {
+                return false;
+            };
+            inject::inject(&mut document, options.injection);
+            content = Content::Memory(document);
         }
 
-        stream.flush().unwrap();
+        // Ranges let media players seek and interrupted downloads resume.
+        let length = content.len();
+        let ranges = request
+            .header("Range")
+            // A range of a version other than the one the client has part of would be garbage.
+            .filter(|_| {
+                request.header("If-Range").is_none_or(|if_range| {
+                    range::if_range_matches(if_range, etag.as_deref(), validated_date)
+                })
+            })
+            .map_or(Ranges::Full, |header| range::parse(header, length));
+
+        // Each part of the body, with the headers that go before it if there are several.
+        let boundary = range::boundary();
+        let (status, headers, parts) = match ranges {
+            Ranges::Full => (
+                "200 OK",
+                format!("Content-type: {content_type}"),
+                vec![(String::new(), 0..length)],
+            ),
+            Ranges::Unsatisfiable => (
+                "416 RANGE NOT SATISFIABLE",
+                format!("Content-Range: bytes */{length}"),
+                Vec::new(),
+            ),
+            Ranges::Partial(ranges) if ranges.len() == 1 => (
+                "206 PARTIAL CONTENT",
+                format!(
+                    "Content-type: {content_type}\r\nContent-Range: {}",
+                    range::content_range(&ranges[0], length)
+                ),
+                vec![(String::new(), ranges[0].clone())],
+            ),
+            Ranges::Partial(ranges) => (
+                "206 PARTIAL CONTENT",
+                format!("Content-type: multipart/byteranges; boundary={boundary}"),
+                ranges
+                    .into_iter()
+                    .map(|range| {
+                        let header = range::part_header(&range, length, content_type, &boundary);
+                        (header, range)
+                    })
+                    .collect(),
+            ),
+        };
+        let closing = if parts.len() > 1 {
+            range::closing(&boundary)
+        } else {
+            String::new()
+        };
+        let body_length = parts
+            .iter()
+            .map(|(header, range)| header.len() as u64 + range.end - range.start)
+            .sum::<u64>()
+            + closing.len() as u64;
+
+        let response = format!(
+            "HTTP/1.1 {}\r\n{}\r\nAccept-Ranges: bytes\r\nContent-Length: {}{}{}{connection}\r\n\r\n",
+            status, headers, body_length, validators, options.headers
+        );
+
+        // The body is written as it's read, so large files don't have to fit in memory.
+        let result = (|| {
+            stream.write_all(response.as_bytes())?;
+            // HEAD gets the same headers as GET, but no body.
+            let parts = if is_head { Vec::new() } else { parts };
+            for (header, range) in parts {
+                stream.write_all(header.as_bytes())?;
+                content.write_range(stream, range)?;
+            }
+            if !is_head {
+                stream.write_all(closing.as_bytes())?;
+            }
+            stream.flush()
+        })();
+        if let Err(err) = result {
+            println!("Could not send [ {} ]: {err}", request.path());
+            return false;
+        }
+        true
     } else {
         println!("Could not find file: {}", path.to_str().unwrap());
-        let response = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
-        stream.write_all(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+        // Projects can provide their own page, otherwise one suggesting similar files is shown.
+        let page = fs::read(options.root_path.join("404.html")).unwrap_or_else(|_| {
+            error_page::not_found(&options.root_path, request.path(), &path).into_bytes()
+        });
+        send_error(stream, is_head, "404 NOT FOUND", connection, page, options);
+        true
+    }
+}
+
+// Identifies a version of a file by when it was changed and its size. HTML the reload script is
+// injected into also depends on the script, so its tag changes when it or the injection point does.
+fn etag(metadata: &fs::Metadata, is_injected: bool, options: &Options) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    if is_injected {
+        let script = inject::version(options.injection);
+        format!("\"{modified:x}-{:x}-{script:x}\"", metadata.len())
+    } else {
+        format!("\"{modified:x}-{:x}\"", metadata.len())
     }
 }
 
-pub fn run(
-    address: IpAddr,
-    port: u16,
-    path: impl AsRef<Path>,
-    watch_path: impl AsRef<Path>,
-    reload: bool,
+// Whether an `If-None-Match` header lists `etag`, using the weak comparison the header calls for.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|tag| {
+        tag == "*"
+            || tag.strip_prefix("W/").unwrap_or(tag) == etag.strip_prefix("W/").unwrap_or(etag)
+    })
+}
+
+// The header telling a client that asked for the connection to close, or can't keep it open,
+// that it closes after the response.
+fn connection_header(request: &Request) -> &'static str {
+    if request.is_persistent() {
+        ""
+    } else {
+        CONNECTION_CLOSE
+    }
+}
+
+// Redirects to `path` on this server, keeping the query string. Returns whether it could be sent.
+fn redirect<T: Write>(mut stream: T, request: &Request, path: &str, options: &Options) -> bool {
+    let location = match request.target.split_once('?') {
+        Some((_, query)) => format!("{path}?{query}"),
+        None => path.to_owned(),
+    };
+    let connection = connection_header(request);
+    let response = format!(
+        "HTTP/1.1 301 MOVED PERMANENTLY\r\nLocation: {location}\r\nContent-Length: 0{}{connection}\r\n\r\n",
+        options.headers
+    );
+    stream
+        .write_all(response.as_bytes())
+        .and_then(|_| stream.flush())
+        .is_ok()
+}
+
+// Sends an HTML page with an error status, and any extra `headers`, each preceded by a line break.
+// The reload script is injected so that the page is replaced once whatever caused the error has been fixed.
+fn send_error<T: Write>(
+    mut stream: T,
+    is_head: bool,
+    status: &str,
     headers: &str,
-    actions: Vec<Action>,
+    mut page: Vec<u8>,
+    options: &Options,
 ) {
+    if options.reload {
+        inject::inject(&mut page, options.injection);
+    }
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-type: {}\r\nContent-Length: {}{}{}\r\n\r\n",
+        status,
+        mime::from_extension(Some("html")),
+        page.len(),
+        headers,
+        options.headers
+    );
+
+    let mut bytes = response.as_bytes().to_vec();
+    if !is_head {
+        bytes.append(&mut page);
+    }
+    // The client may have given up already, which isn't worth panicking over.
+    let _ = stream.write_all(&bytes);
+    let _ = stream.flush();
+}
+
+pub fn run(address: IpAddr, port: u16, options: Options, watch: WatchOptions) {
     let acceptor = {
         // Hard coded certificate generated with the following commands:
         // openssl req -x509 -newkey rsa:2048 -keyout key.pem -out cert.pem -days 36500 -nodes -subj "/"
@@ -145,36 +716,43 @@ pub fn run(
         )
     };
 
+    let hub = Arc::new(reload::Hub::default());
+
     {
-        let watch_path = watch_path.as_ref().to_owned();
+        let root_path = options.root_path.clone();
+        let hub = hub.clone();
         thread::spawn(move || {
-            let watch_path = watch_path.clone();
-            reload::watch_for_reloads(address, &watch_path, actions);
+            watch::watch_for_reloads(&root_path, watch, &hub);
         });
     }
 
+    let options = Arc::new(options);
     let listener = TcpListener::bind((address, port)).unwrap();
     for stream in listener.incoming().flatten() {
         let acceptor = acceptor.clone();
 
-        let path = path.as_ref().to_owned();
-        let headers = headers.to_owned();
+        let options = options.clone();
+        let hub = hub.clone();
         thread::spawn(move || {
             // HTTP requests always begin with a verb like 'GET'.
             // HTTPS requests begin with a number, so peeking and checking for a number
             // is used to determine if a request is HTTPS or HTTP
             let mut buf = [0; 2];
-            stream.peek(&mut buf).expect("peek failed");
+            // Connections opened ahead of time may never be used.
+            let timeout = stream.set_read_timeout(Some(KEEP_ALIVE_TIMEOUT));
+            if timeout.is_err() || stream.peek(&mut buf).is_err() {
+                return;
+            }
 
             let is_https = !((buf[0] as char).is_alphabetic() && (buf[1] as char).is_alphabetic());
 
             if is_https {
                 // acceptor.accept will block indefinitely if called with an HTTP stream.
                 if let Ok(stream) = acceptor.accept(stream) {
-                    handle_client(stream, &path, reload, &headers);
+                    handle_client(stream, &options, &hub);
                 }
             } else {
-                handle_client(stream, &path, reload, &headers);
+                handle_client(stream, &options, &hub);
             }
         });
     }
@@ -0,0 +1,207 @@
+//! Generated index pages for folders without an `index.html`.
+
+use crate::{error_page::escape, json, request::Request};
+use std::{
+    cmp::Ordering,
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+struct Entry {
+    name: String,
+    is_folder: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Name,
+    Size,
+    Modified,
+}
+
+impl Column {
+    const ALL: [Column; 3] = [Column::Name, Column::Size, Column::Modified];
+
+    fn from_query(value: Option<&str>) -> Self {
+        match value {
+            Some("size") => Column::Size,
+            Some("modified") => Column::Modified,
+            _ => Column::Name,
+        }
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            Column::Name => "name",
+            Column::Size => "size",
+            Column::Modified => "modified",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Column::Name => "Name",
+            Column::Size => "Size",
+            Column::Modified => "Modified",
+        }
+    }
+
+    fn compare(self, a: &Entry, b: &Entry) -> Ordering {
+        match self {
+            Column::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            Column::Size => a.size.cmp(&b.size),
+            Column::Modified => a.modified.cmp(&b.modified),
+        }
+    }
+}
+
+/// List `folder`, which was requested as `request`, returning the listing and the file extension
+/// matching its format. The format is JSON if the query has `format=json` and HTML otherwise.
+/// HTML listings can be sorted with the `sort` parameter, by `name`, `size` or `modified`,
+/// and reversed with `order=desc`.
+pub(crate) fn list(folder: &Path, request: &Request) -> io::Result<(Vec<u8>, &'static str)> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follows links, so a link to a folder is listed as a folder.
+        let Ok(metadata) = fs::metadata(entry.path()) else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_folder: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    let column = Column::from_query(request.query("sort"));
+    let descending = request.query("order") == Some("desc");
+    entries.sort_by(|a, b| {
+        let order = column.compare(a, b);
+        let order = if descending { order.reverse() } else { order };
+        // Folders stay at the top whatever the order.
+        b.is_folder.cmp(&a.is_folder).then(order)
+    });
+
+    if request.query("format") == Some("json") {
+        Ok((to_json(&entries).into_bytes(), "json"))
+    } else {
+        let page = to_html(request.path(), &entries, column, descending);
+        Ok((page.into_bytes(), "html"))
+    }
+}
+
+fn to_json(entries: &[Entry]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or("null".to_owned(), |since| since.as_secs().to_string());
+            format!(
+                "{{\"name\":{},\"type\":{},\"size\":{},\"modified\":{}}}",
+                json::string(&entry.name),
+                json::string(if entry.is_folder { "directory" } else { "file" }),
+                entry.size,
+                modified
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+fn to_html(url_path: &str, entries: &[Entry], sorted_by: Column, descending: bool) -> String {
+    let title = escape(&url_path.replace("%20", " "));
+
+    // Links to each folder above this one.
+    let mut breadcrumbs = String::from("<a href=\"/\">/</a>");
+    let mut href = String::from("/");
+    for segment in url_path.split('/').filter(|segment| !segment.is_empty()) {
+        href.push_str(segment);
+        href.push('/');
+        breadcrumbs.push_str(&format!(
+            "<a href=\"{}\">{}</a>/",
+            escape(&href),
+            escape(&segment.replace("%20", " "))
+        ));
+    }
+
+    // Choosing the column already sorted by reverses the order.
+    let mut header = String::new();
+    for column in Column::ALL {
+        let order = if column == sorted_by && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        let arrow = match (column == sorted_by, descending) {
+            (false, _) => "",
+            (true, false) => " &#9650;",
+            (true, true) => " &#9660;",
+        };
+        header.push_str(&format!(
+            "<th><a href=\"?sort={}&amp;order={order}\">{}</a>{arrow}</th>",
+            column.key(),
+            column.title()
+        ));
+    }
+
+    let mut rows = String::new();
+    if url_path != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let name = if entry.is_folder {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        let size = if entry.is_folder {
+            "-".to_owned()
+        } else {
+            format_size(entry.size)
+        };
+        let modified = entry
+            .modified
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{size}</td><td>{modified}</td></tr>\n",
+            escape(&name.replace(' ', "%20")),
+            escape(&name)
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n\
+         <style>td, th {{ padding: 0 1em 0 0; text-align: left; }}</style>\n</head>\n<body>\n\
+         <h1>Index of {breadcrumbs}</h1>\n<table>\n<tr>{header}</tr>\n{rows}</table>\n</body>\n</html>\n"
+    )
+}
+
+// A size like `512 B` or `1.5 MiB`.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{size:.1} {unit}")
+}
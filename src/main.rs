@@ -1,11 +1,10 @@
 use anyhow::{bail, Result};
 use clap::Parser;
 use std::env;
-use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr};
 
 use std::path::PathBuf;
-use std::process::Command;
+use std::time::Duration;
 
 const DEFAULT_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 const DEFAULT_PORT: u16 = 8080;
@@ -16,9 +15,18 @@ struct Arguments {
     /// Command to run when source files change
     #[clap(short, long)]
     command: Option<String>,
+    /// Long running process to start alongside the server, like an API, restarted when source files change
+    #[clap(long)]
+    run: Option<String>,
+    /// Port the --run process listens on, the page is reloaded once it accepts connections
+    #[clap(long, requires = "run")]
+    run_port: Option<u16>,
     /// Don't automatically reload
     #[clap(long)]
     noreload: bool,
+    /// Where to inject the reload script in HTML documents: 'body' or 'head'
+    #[clap(long, default_value = "body")]
+    inject: devserver::Injection,
     /// The port number
     #[clap(short, long, default_value_t=DEFAULT_PORT)]
     port: u16,
@@ -28,36 +36,82 @@ struct Arguments {
     /// Extra headers to serve
     #[clap(long)]
     header: Vec<String>,
+    /// Files to serve for a folder, in order of preference
+    #[clap(long = "index", value_delimiter = ',', default_value = "index.html")]
+    index_files: Vec<String>,
+    /// Extensions to try, in order, for files requested without one
+    #[clap(long, value_delimiter = ',', default_value = "html")]
+    extensions: Vec<String>,
+    /// Redirect '/page.html' to '/page' and '/folder/index.html' to '/folder/'
+    #[clap(long)]
+    clean_urls: bool,
+    /// Don't list the contents of folders without an index.html
+    #[clap(long)]
+    no_listing: bool,
+    /// For single page apps: serve this page, or index.html, in place of pages that don't exist
+    #[clap(
+        long,
+        value_name = "FALLBACK",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "index.html"
+    )]
+    spa: Option<PathBuf>,
+    /// Forward requests under a path to another server, like '/api=http://127.0.0.1:3000'
+    #[clap(long = "proxy", value_name = "PATH=URL")]
+    proxies: Vec<devserver::Proxy>,
     /// Path to watch
     #[clap(short = 'w', long = "watch")]
     watch_path: Option<PathBuf>,
+    /// Run a command when files matching a path or glob change, like 'shaders/**/*.glsl=make'
+    #[clap(long = "on", value_name = "PATH=COMMAND", value_parser = parse_rule)]
+    rules: Vec<(PathBuf, String)>,
+    /// Kinds of file events that count as a change: create, modify, remove, rename
+    #[clap(
+        long,
+        value_delimiter = ',',
+        default_value = "create,modify,remove,rename"
+    )]
+    events: Vec<devserver::WatchEvent>,
+    /// How long to wait for changes to stop before rebuilding, like '150ms' or '1s'
+    #[clap(long, default_value = "100ms", value_parser = parse_duration)]
+    debounce: Duration,
+    /// Gitignore style pattern for files that shouldn't trigger a reload, like 'dist/' or '*.log'
+    #[clap(long)]
+    ignore: Vec<String>,
+    /// Don't skip files listed in .gitignore and .ignore files
+    #[clap(long)]
+    no_ignore_files: bool,
+    /// File or folder written by the command, which reloads the page without running it again.
+    /// With outputs declared, any other change made while the command runs stops it and starts it again
+    #[clap(long = "output")]
+    outputs: Vec<PathBuf>,
     /// The path to serve
     path: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    let args = Arguments::parse();
-    let mut actions = Vec::new();
-    if let Some(cmd) = args.command {
-        let func: devserver::Action = Box::new(move || {
-            let mut command = Command::new("sh");
-            let command = command.arg("-c").arg(&cmd);
-            let output = command.output().map_err(|err| {
-                let err: Box<dyn Display> = Box::new(err);
-                err
-            })?;
-            let stdout = String::from_utf8(output.stdout).unwrap();
-            print!("{stdout}");
-            let stderr = String::from_utf8(output.stderr).unwrap();
-            print!("{stderr}");
-            if !output.status.success() {
-                return Err(Box::new(format!("{stdout}{stderr}")));
-            }
-            Ok(())
-        });
-        actions.push(func);
+fn parse_rule(rule: &str) -> Result<(PathBuf, String)> {
+    let Some((path, command)) = rule.split_once('=') else {
+        bail!("Expected a path and a command separated by '='");
+    };
+    Ok((PathBuf::from(path), command.to_owned()))
+}
+
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => duration.split_at(index),
+        None => (duration, "ms"),
     };
+    let number: u64 = number.parse()?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        _ => bail!("Unknown unit '{unit}', expected 'ms' or 's'"),
+    }
+}
 
+fn main() -> Result<()> {
+    let args = Arguments::parse();
     let mut headers = String::new();
     for header in args.header.iter().cloned().map(|h| {
         if !h.contains(':') {
@@ -100,15 +154,53 @@ fn main() -> Result<()> {
 
     println!("Stop with Ctrl+C");
 
-    devserver::run(
-        args.bind,
-        args.port,
-        hosted_path,
-        watch_path,
-        !args.noreload,
-        &headers,
+    let options = devserver::Options {
+        root_path: hosted_path,
+        reload: !args.noreload,
+        injection: args.inject,
+        headers,
+        index_files: args.index_files,
+        extensions: args.extensions,
+        clean_urls: args.clean_urls,
+        listing: !args.no_listing,
+        fallback: args.spa,
+        proxies: args.proxies,
+    };
+
+    // The process is restarted after the command, so it picks up whatever was built.
+    let mut actions: Vec<_> = args
+        .command
+        .into_iter()
+        .map(devserver::shell_action)
+        .collect();
+    if let Some(run) = args.run {
+        actions.push(devserver::process_action(run, args.run_port));
+    }
+    let mut rules = vec![devserver::WatchRule {
+        path: watch_path,
         actions,
-    );
+    }];
+    for (path, command) in args.rules {
+        rules.push(devserver::WatchRule {
+            path: env::current_dir()?.join(path),
+            actions: vec![devserver::shell_action(command)],
+        });
+    }
+
+    let watch = devserver::WatchOptions {
+        rules,
+        events: args.events,
+        debounce: args.debounce,
+        ignore: args.ignore,
+        ignore_files: !args.no_ignore_files,
+        outputs: args
+            .outputs
+            .iter()
+            .map(|output| Ok(env::current_dir()?.join(output)))
+            .collect::<Result<_>>()?,
+    };
+
+    devserver::run(args.bind, args.port, options, watch);
 
     Ok(())
 }
@@ -0,0 +1,157 @@
+//! Finding where HTTP message bodies end, so they can be passed on or skipped.
+
+use crate::request::{Headers, Request};
+use std::{
+    io::{self, BufRead, ErrorKind, Read, Write},
+    str,
+};
+
+/// How the end of a message body is found.
+pub(crate) enum Body {
+    None,
+    Length(u64),
+    Chunked,
+    /// Everything until the connection is closed.
+    UntilClose,
+}
+
+impl Body {
+    pub(crate) fn of_request(request: &Request) -> Self {
+        if request.header_contains("Transfer-Encoding", "chunked") {
+            Body::Chunked
+        } else {
+            match request.header("Content-Length").map(str::parse) {
+                Some(Ok(length)) => Body::Length(length),
+                _ => Body::None,
+            }
+        }
+    }
+
+    pub(crate) fn of_response(request: &Request, status: u16, headers: &Headers) -> Self {
+        if request.method == "HEAD" || status < 200 || status == 204 || status == 304 {
+            Body::None
+        } else if headers.contains("Transfer-Encoding", "chunked") {
+            Body::Chunked
+        } else {
+            match headers.get("Content-Length").map(str::parse) {
+                Some(Ok(length)) => Body::Length(length),
+                _ => Body::UntilClose,
+            }
+        }
+    }
+}
+
+/// Copy a message body, stopping where it ends so that nothing after it is consumed.
+pub(crate) fn relay_body<R: BufRead, W: Write>(
+    mut from: R,
+    mut to: W,
+    body: Body,
+) -> io::Result<()> {
+    match body {
+        Body::None => Ok(()),
+        Body::Length(length) => copy_exact(&mut from, &mut to, length),
+        Body::Chunked => loop {
+            // Chunks are passed on as they are, so the receiver sees the same framing.
+            let line = read_line(&mut from)?;
+            to.write_all(&line)?;
+            let size = str::from_utf8(&line)
+                .ok()
+                .and_then(|line| line.split(';').next())
+                .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Malformed chunk size"))?;
+
+            if size == 0 {
+                // Trailers, up to the empty line that ends the body.
+                loop {
+                    let line = read_line(&mut from)?;
+                    to.write_all(&line)?;
+                    if line.is_empty() || line == b"\r\n" {
+                        return Ok(());
+                    }
+                }
+            }
+            copy_exact(&mut from, &mut to, size + 2)?;
+            // Chunks are often events the page is waiting for, so they're sent straight away.
+            to.flush()?;
+        },
+        Body::UntilClose => {
+            let mut buffer = [0; 16 * 1024];
+            loop {
+                let read = from.read(&mut buffer)?;
+                if read == 0 {
+                    return Ok(());
+                }
+                to.write_all(&buffer[..read])?;
+                to.flush()?;
+            }
+        }
+    }
+}
+
+fn copy_exact<R: Read, W: Write>(from: &mut R, to: &mut W, length: u64) -> io::Result<()> {
+    if io::copy(&mut from.take(length), to)? < length {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+// Reads a line including its line break, leaving anything after it in the buffer.
+fn read_line<R: BufRead>(from: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    from.read_until(b'\n', &mut line)?;
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Relays a body from the start of `input`, returning what was passed on and what was left.
+    fn relay(input: &[u8], body: Body) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut from = input;
+        let mut to = Vec::new();
+        relay_body(&mut from, &mut to, body)?;
+        Ok((to, from.to_vec()))
+    }
+
+    #[test]
+    fn chunked_bodies_are_passed_on_as_they_are() {
+        let body = b"4;name=\"value\"\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n\
+                     0\r\nExpires: never\r\nX-Checksum: 42\r\n\r\n";
+        let input = [&body[..], b"GET /next HTTP/1.1\r\n\r\n"].concat();
+        let (relayed, rest) = relay(&input, Body::Chunked).unwrap();
+        assert_eq!(relayed, body);
+        assert_eq!(rest, b"GET /next HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn the_last_chunk_ends_the_body() {
+        let input = b"3\r\nabc\r\n0\r\n\r\n3\r\ndef\r\n";
+        let (relayed, rest) = relay(input, Body::Chunked).unwrap();
+        assert_eq!(relayed, b"3\r\nabc\r\n0\r\n\r\n");
+        assert_eq!(rest, b"3\r\ndef\r\n");
+    }
+
+    #[test]
+    fn broken_chunked_bodies_are_errors() {
+        let malformed = relay(b"xyz\r\nabc\r\n0\r\n\r\n", Body::Chunked);
+        assert_eq!(malformed.unwrap_err().kind(), ErrorKind::InvalidData);
+        let truncated = relay(b"10\r\nabc", Body::Chunked);
+        assert_eq!(truncated.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn other_bodies_stop_at_their_end() {
+        let (relayed, rest) = relay(b"hello world", Body::Length(5)).unwrap();
+        assert_eq!((&relayed[..], &rest[..]), (&b"hello"[..], &b" world"[..]));
+
+        let (relayed, rest) = relay(b"GET / HTTP/1.1", Body::None).unwrap();
+        assert_eq!((relayed.len(), &rest[..]), (0, &b"GET / HTTP/1.1"[..]));
+
+        let (relayed, rest) = relay(b"everything", Body::UntilClose).unwrap();
+        assert_eq!((&relayed[..], rest.len()), (&b"everything"[..], 0));
+
+        let short = relay(b"abc", Body::Length(5));
+        assert_eq!(short.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -576,3 +576,19 @@ pub(crate) fn from_extension(extension: Option<&str>) -> &'static str {
         _ => "application/octet-stream",
     }
 }
+
+/// Guesses if a file without an extension is an HTML document by looking at how it starts.
+pub(crate) fn is_html_document(contents: &[u8]) -> bool {
+    let contents = contents.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(contents);
+    let start = contents
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(contents.len());
+    let contents = &contents[start..];
+
+    [&b"<!doctype html"[..], b"<html"].iter().any(|prefix| {
+        contents
+            .get(..prefix.len())
+            .is_some_and(|c| c.eq_ignore_ascii_case(prefix))
+    })
+}
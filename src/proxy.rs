@@ -0,0 +1,355 @@
+//! Forwarding requests to another HTTP server, such as an API backend.
+
+use crate::{
+    error_page,
+    message::{relay_body, Body},
+    mime, read_header,
+    request::{Headers, Request},
+    Buffered, Connection,
+};
+use std::{
+    fmt::Display,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    str::{self, FromStr},
+    time::Duration,
+};
+
+/// How long each side of a proxied WebSocket is waited on before checking the other.
+const PUMP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Headers that only describe a single connection, so they aren't passed on.
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authorization",
+    "TE",
+    "Upgrade",
+];
+
+/// Forwards requests under a path to another HTTP server.
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    /// Requests whose path is this or starts with it, such as `/api`, are forwarded.
+    pub prefix: String,
+    /// The `host:port` of the upstream server.
+    pub authority: String,
+    /// Replaces the prefix in forwarded requests when the upstream URL has a path.
+    pub path: Option<String>,
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    /// Parse a rule like `/api=http://127.0.0.1:3000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((prefix, url)) = s.split_once('=') else {
+            return Err("Expected a path and a URL separated by '='".to_owned());
+        };
+        if !prefix.starts_with('/') {
+            return Err(format!("Proxied path '{prefix}' must start with '/'"));
+        }
+        let Some(url) = url.strip_prefix("http://") else {
+            return Err(format!(
+                "Only http:// upstream URLs are supported, got '{url}'"
+            ));
+        };
+        let (authority, path) = match url.find('/') {
+            Some(index) => url.split_at(index),
+            None => (url, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("Upstream URL 'http://{url}' has no host"));
+        }
+        let authority = if authority.contains(':') {
+            authority.to_owned()
+        } else {
+            format!("{authority}:80")
+        };
+        let path = match path.trim_end_matches('/') {
+            "" => None,
+            path => Some(path.to_owned()),
+        };
+
+        Ok(Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            authority,
+            path,
+        })
+    }
+}
+
+impl Proxy {
+    /// Whether requests for `path` go to this upstream.
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || self.prefix.is_empty())
+    }
+
+    // The target to request upstream, with the prefix swapped for the upstream path if it has one.
+    fn target(&self, target: &str) -> String {
+        match &self.path {
+            Some(path) => format!("{path}{}", &target[self.prefix.len()..]),
+            None => target.to_owned(),
+        }
+    }
+}
+
+/// Forward `request` and its body to the upstream server, then pass the response back.
+/// Returns whether the connection can be used for another request.
+pub(crate) fn forward<T: Connection>(
+    stream: &mut Buffered<T>,
+    request: &Request,
+    proxy: &Proxy,
+) -> bool {
+    let log = |err: &dyn Display| {
+        println!(
+            "Could not proxy [ {} ] to [ {} ]: {err}",
+            request.target, proxy.authority
+        );
+    };
+
+    let response = send_request(stream, request, proxy).and_then(|upstream| {
+        let reader = BufReader::new(upstream.try_clone()?);
+        Ok((upstream, reader))
+    });
+    let (mut upstream, mut reader) = match response {
+        Ok(response) => response,
+        Err(err) => {
+            log(&err);
+            bad_gateway(stream, proxy);
+            return false;
+        }
+    };
+
+    // Interim responses, such as `103 Early Hints`, come before the real one and are passed on as they are.
+    let header = loop {
+        let header = read_header(&mut reader);
+        let is_interim = parse_response(&header)
+            .is_some_and(|(_, status, _)| (100..200).contains(&status) && status != 101);
+        if !is_interim {
+            break header;
+        }
+        if let Err(err) = stream.write_all(&header).and_then(|_| stream.flush()) {
+            log(&err);
+            return false;
+        }
+    };
+    let Some((status_line, status, headers)) = parse_response(&header) else {
+        log(&"Malformed response");
+        bad_gateway(stream, proxy);
+        return false;
+    };
+
+    let mut header = format!("{status_line}\r\n");
+    for (name, value) in headers.iter() {
+        if !is_hop_by_hop(name, &headers) {
+            header.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    let is_upgrade = status == 101 && request.header_contains("Connection", "upgrade");
+    let protocol = headers.get("Upgrade").filter(|_| is_upgrade);
+    let body = Body::of_response(request, status, &headers);
+    // Only a body that ends by closing the connection, or a switch to another protocol, uses it up.
+    let is_reusable = protocol.is_none() && !matches!(body, Body::UntilClose);
+    if let Some(protocol) = protocol {
+        header.push_str(&format!("Connection: Upgrade\r\nUpgrade: {protocol}\r\n"));
+    } else if !is_reusable || !request.is_persistent() {
+        header.push_str("Connection: close\r\n");
+    }
+    header.push_str("\r\n");
+    let result = stream.write_all(header.as_bytes()).and_then(|_| {
+        if protocol.is_some() {
+            // Anything the upstream sent straight after switching protocols is already buffered.
+            stream.write_all(reader.buffer())?;
+            stream.flush()?;
+            pump(stream, &mut upstream)
+        } else {
+            relay_body(&mut reader, &mut *stream, body)?;
+            stream.flush()
+        }
+    });
+    if let Err(err) = result {
+        log(&err);
+        return false;
+    }
+    is_reusable
+}
+
+fn bad_gateway<T: Write>(mut stream: T, proxy: &Proxy) {
+    let message = format!(
+        "<p>Could not get a response from <code>{}</code>.</p>",
+        error_page::escape(&proxy.authority)
+    );
+    let page = error_page::render("502 Bad Gateway", &message);
+    let response = format!(
+        "HTTP/1.1 502 BAD GATEWAY\r\nContent-type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{page}",
+        mime::from_extension(Some("html")),
+        page.len()
+    );
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.flush();
+}
+
+// Connects to the upstream server and sends it the request, including its body.
+fn send_request<T: Connection>(
+    stream: &mut Buffered<T>,
+    request: &Request,
+    proxy: &Proxy,
+) -> io::Result<TcpStream> {
+    let mut upstream = TcpStream::connect(&proxy.authority)?;
+
+    let mut header = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+        request.method,
+        proxy.target(&request.target),
+        proxy.authority
+    );
+    for (name, value) in request.headers.iter() {
+        let is_forwarded = name.eq_ignore_ascii_case("X-Forwarded-For")
+            || name.eq_ignore_ascii_case("X-Forwarded-Host");
+        if !name.eq_ignore_ascii_case("Host")
+            && !name.eq_ignore_ascii_case("Expect")
+            && !is_forwarded
+            && !is_hop_by_hop(name, &request.headers)
+        {
+            header.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    let client = stream.socket().peer_addr()?.ip();
+    let forwarded_for = match request.header("X-Forwarded-For") {
+        Some(previous) => format!("{previous}, {client}"),
+        None => client.to_string(),
+    };
+    header.push_str(&format!("X-Forwarded-For: {forwarded_for}\r\n"));
+    if let Some(host) = request.header("Host") {
+        header.push_str(&format!("X-Forwarded-Host: {host}\r\n"));
+    }
+    // The body is sent straight after the header, so the client is told to go ahead rather than
+    // the upstream being asked.
+    if request.header_contains("Expect", "100-continue") && request.version == "HTTP/1.1" {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        stream.flush()?;
+    }
+    let upgrade = request
+        .header("Upgrade")
+        .filter(|_| request.header_contains("Connection", "upgrade"));
+    match upgrade {
+        Some(protocol) => {
+            header.push_str(&format!("Connection: Upgrade\r\nUpgrade: {protocol}\r\n"))
+        }
+        // Every request gets a connection of its own, so the response can't run into the next.
+        None => header.push_str("Connection: close\r\n"),
+    }
+    header.push_str("\r\n");
+
+    upstream.write_all(header.as_bytes())?;
+    relay_body(&mut *stream, &mut upstream, Body::of_request(request))?;
+    upstream.flush()?;
+    Ok(upstream)
+}
+
+// Whether a header only applies to a single connection, including any the `Connection` header lists.
+fn is_hop_by_hop(name: &str, headers: &Headers) -> bool {
+    HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+        || headers.contains("Connection", name)
+}
+
+// Splits a response header into its status line, status code and header fields.
+fn parse_response(bytes: &[u8]) -> Option<(&str, u16, Headers)> {
+    let response = str::from_utf8(bytes).ok()?;
+    let mut lines = response.split("\r\n");
+    let status_line = lines.next()?;
+    let mut parts = status_line.split(' ');
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let status = parts.next()?.parse().ok()?;
+    Some((status_line, status, Headers::parse(lines)?))
+}
+
+// Passes data both ways between a client and upstream after switching to another protocol,
+// until either side closes.
+fn pump<T: Connection>(stream: &mut T, upstream: &mut TcpStream) -> io::Result<()> {
+    stream.socket().set_read_timeout(Some(PUMP_INTERVAL))?;
+    upstream.set_read_timeout(Some(PUMP_INTERVAL))?;
+    let mut buffer = [0; 16 * 1024];
+    while pass_on(&mut *stream, &mut *upstream, &mut buffer)?
+        && pass_on(&mut *upstream, &mut *stream, &mut buffer)?
+    {}
+    Ok(())
+}
+
+// Passes on whatever arrives before the read timeout. Returns false once `from` is closed.
+fn pass_on<R: Read, W: Write>(mut from: R, mut to: W, buffer: &mut [u8]) -> io::Result<bool> {
+    match from.read(buffer) {
+        Ok(0) => Ok(false),
+        Ok(read) => {
+            to.write_all(&buffer[..read])?;
+            to.flush()?;
+            Ok(true)
+        }
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rule: &str) -> (String, String, Option<String>) {
+        let proxy: Proxy = rule.parse().unwrap();
+        (proxy.prefix, proxy.authority, proxy.path)
+    }
+
+    #[test]
+    fn rules_are_parsed() {
+        assert_eq!(
+            parse("/api=http://127.0.0.1:3000"),
+            ("/api".into(), "127.0.0.1:3000".into(), None)
+        );
+        assert_eq!(
+            parse("/api/=http://localhost/"),
+            ("/api".into(), "localhost:80".into(), None)
+        );
+        assert_eq!(
+            parse("/api=http://localhost:3000/v1/"),
+            ("/api".into(), "localhost:3000".into(), Some("/v1".into()))
+        );
+        assert_eq!(
+            parse("/=http://[::1]:8000"),
+            ("".into(), "[::1]:8000".into(), None)
+        );
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for rule in [
+            "/api",
+            "api=http://localhost:3000",
+            "/api=https://localhost:3000",
+            "/api=localhost:3000",
+            "/api=http://",
+            "/api=http:///v1",
+        ] {
+            assert!(rule.parse::<Proxy>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let proxy: Proxy = "/api=http://localhost:3000/v1".parse().unwrap();
+        assert!(proxy.matches("/api"));
+        assert!(proxy.matches("/api/users"));
+        assert!(!proxy.matches("/apis"));
+        assert!(!proxy.matches("/"));
+        assert_eq!(proxy.target("/api/users?page=2"), "/v1/users?page=2");
+
+        let everything: Proxy = "/=http://localhost:3000".parse().unwrap();
+        assert!(everything.matches("/"));
+        assert!(everything.matches("/anything"));
+        assert_eq!(everything.target("/anything"), "/anything");
+    }
+}
@@ -0,0 +1,212 @@
+//! Parsing `Range` headers and building partial responses.
+
+use httpdate::HttpDate;
+use std::{
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// More ranges than this are answered with the whole file, which is cheaper for everyone.
+const MAX_RANGES: usize = 16;
+
+/// What to send in response to a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Ranges {
+    /// The header can't be used, so the whole body is sent.
+    Full,
+    /// These parts of the body, in the order they were asked for.
+    Partial(Vec<Range<u64>>),
+    /// None of the ranges are in the body.
+    Unsatisfiable,
+}
+
+/// Parse a header like `bytes=0-499, -200` for a body `length` bytes long.
+pub(crate) fn parse(header: &str, length: u64) -> Ranges {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return Ranges::Full;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        let Some((start, end)) = spec.split_once('-') else {
+            return Ranges::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+        let range = if start.is_empty() {
+            // The last `end` bytes.
+            let Ok(suffix) = end.parse::<u64>() else {
+                return Ranges::Full;
+            };
+            // An empty body has no last bytes to send.
+            if suffix == 0 || length == 0 {
+                continue;
+            }
+            length.saturating_sub(suffix)..length
+        } else {
+            let Ok(start) = start.parse::<u64>() else {
+                return Ranges::Full;
+            };
+            let end = match end {
+                "" => length,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.saturating_add(1).min(length),
+                    _ => return Ranges::Full,
+                },
+            };
+            if start >= length {
+                continue;
+            }
+            start..end
+        };
+        ranges.push(range);
+    }
+
+    match ranges.len() {
+        0 => Ranges::Unsatisfiable,
+        count if count > MAX_RANGES => Ranges::Full,
+        _ => Ranges::Partial(ranges),
+    }
+}
+
+/// Whether an `If-Range` header still matches the body, so the ranges can be sent.
+/// Only strong validators count: an exact entity tag, or the exact modification time.
+pub(crate) fn if_range_matches(
+    header: &str,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+) -> bool {
+    let header = header.trim();
+    if header.starts_with('"') || header.starts_with("W/") {
+        return etag.is_some_and(|etag| !etag.starts_with("W/") && etag == header);
+    }
+    // Dates only have whole seconds, which `HttpDate` compares by.
+    let date = header.parse::<HttpDate>();
+    modified.is_some_and(|modified| date.is_ok_and(|date| date == HttpDate::from(modified)))
+}
+
+/// The `Content-Range` value for `range` of a body `length` bytes long.
+pub(crate) fn content_range(range: &Range<u64>, length: u64) -> String {
+    format!("bytes {}-{}/{length}", range.start, range.end - 1)
+}
+
+/// A separator for the parts of a multipart body that is unlikely to appear in them.
+pub(crate) fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos());
+    format!("devserver-{nanos:x}")
+}
+
+/// The separator and headers that come before `range` in a `multipart/byteranges` body.
+pub(crate) fn part_header(
+    range: &Range<u64>,
+    length: u64,
+    content_type: &str,
+    boundary: &str,
+) -> String {
+    format!(
+        "\r\n--{boundary}\r\nContent-type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+        content_range(range, length)
+    )
+}
+
+/// What ends a `multipart/byteranges` body.
+pub(crate) fn closing(boundary: &str) -> String {
+    format!("\r\n--{boundary}--\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn single(range: Range<u64>) -> Ranges {
+        Ranges::Partial(vec![range])
+    }
+
+    fn partial(ranges: &[Range<u64>]) -> Ranges {
+        Ranges::Partial(ranges.to_vec())
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(parse("bytes=0-499", 1000), single(0..500));
+        assert_eq!(parse(" bytes=10-10 ", 1000), single(10..11));
+        assert_eq!(
+            parse("bytes=0-0, 500-599,-1", 1000),
+            partial(&[0..1, 500..600, 999..1000])
+        );
+    }
+
+    #[test]
+    fn suffix_ranges_count_from_the_end() {
+        assert_eq!(parse("bytes=-200", 1000), single(800..1000));
+        assert_eq!(parse("bytes=-2000", 1000), single(0..1000));
+        assert_eq!(parse("bytes=-0", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-10", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn open_and_overlong_ranges_stop_at_the_end() {
+        assert_eq!(parse("bytes=900-", 1000), single(900..1000));
+        assert_eq!(parse("bytes=900-5000", 1000), single(900..1000));
+        assert_eq!(
+            parse(&format!("bytes=0-{}", u64::MAX), 1000),
+            single(0..1000)
+        );
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=1000-1999, 5000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+        // Only the ranges that overlap the body are sent.
+        assert_eq!(parse("bytes=2000-, 0-9", 1000), single(0..10));
+    }
+
+    #[test]
+    fn unusable_headers_get_the_whole_body() {
+        for header in [
+            "items=0-1",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=1",
+            "bytes=--1",
+            "bytes=0-1-2",
+        ] {
+            assert_eq!(parse(header, 1000), Ranges::Full, "{header}");
+        }
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(parse(&format!("bytes={many}"), 1000), Ranges::Full);
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+        let etag = Some("\"abc-12\"");
+        assert!(if_range_matches("\"abc-12\"", etag, None));
+        assert!(!if_range_matches("\"abc-13\"", etag, None));
+        assert!(!if_range_matches("W/\"abc-12\"", etag, None));
+        assert!(!if_range_matches(
+            "W/\"abc-12\"",
+            Some("W/\"abc-12\""),
+            None
+        ));
+        assert!(!if_range_matches("\"abc-12\"", None, None));
+    }
+
+    #[test]
+    fn if_range_dates_match_to_the_second() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        let date = httpdate::fmt_http_date(modified);
+        assert!(if_range_matches(&date, None, Some(modified)));
+        let later = httpdate::fmt_http_date(modified + Duration::from_secs(1));
+        assert!(!if_range_matches(&later, None, Some(modified)));
+        assert!(!if_range_matches(&date, None, None));
+        assert!(!if_range_matches("yesterday", None, Some(modified)));
+    }
+}
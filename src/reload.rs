@@ -1,30 +1,20 @@
+//! The websocket that tells pages when to reload, and the hub that feeds it messages.
+
+use crate::{json, request::Request, Connection};
 use base64::{engine::general_purpose::STANDARD as STD_BASE64, Engine as _};
-use notify::{event::ModifyKind, Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use sha1::{Digest, Sha1};
 use std::{
-    io::{Read, Write},
-    net::{IpAddr, TcpListener},
-    path::Path,
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
     str,
-    sync::{mpsc, Arc, Condvar, Mutex},
-    thread,
-    time::Duration,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
-pub const RELOAD_PORT: u16 = 8129; /* Arbitrary port */
-
-fn parse_websocket_handshake(bytes: &[u8]) -> String {
-    let request_string = str::from_utf8(bytes).unwrap();
-    let lines = request_string.split("\r\n");
-    let mut sec_websocket_key = "";
-
-    for line in lines {
-        let parts: Vec<&str> = line.split(':').collect();
-        if let "Sec-WebSocket-Key" = parts[0] {
-            sec_websocket_key = parts[1].trim();
-        }
-    }
+/// Requests for this path are upgraded to the websocket used to signal reloads.
+pub(crate) const RELOAD_PATH: &str = "/__devserver/ws";
 
+fn websocket_accept_key(sec_websocket_key: &str) -> String {
     // Perform a ceremony of getting the SHA1 hash of the sec_websocket_key joined with
     // an arbitrary string and then take the base 64 encoding of that.
     let sec_websocket_accept = format!(
@@ -34,97 +24,445 @@ fn parse_websocket_handshake(bytes: &[u8]) -> String {
     let mut hasher = Sha1::new();
     hasher.update(sec_websocket_accept.as_bytes());
     let result = hasher.finalize();
-    let bytes = STD_BASE64.encode(result);
+    STD_BASE64.encode(result)
+}
 
-    format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",bytes)
+/// How often a connection checks for client frames and new messages to send.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a connection may sit idle before a ping is sent to check it is still alive.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// How long to wait for a pong (or a close reply) before giving up on a client.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clients only send control frames, so anything larger than this is suspicious.
+const MAX_CLIENT_PAYLOAD: u64 = 64 * 1024;
+/// How many broadcast messages are kept for connections that haven't sent them yet.
+const BACKLOG: usize = 16;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
 }
 
-// This function can send strings of text to a websocket stream.
-fn send_websocket_message<T: Write>(
-    mut stream: T,
-    message: Option<String>,
-) -> Result<(), std::io::Error> {
-    let payload_length = message.as_ref().map(|v| v.len()).unwrap_or(0);
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x0 => Opcode::Continuation,
+            0x1 => Opcode::Text,
+            0x2 => Opcode::Binary,
+            0x8 => Opcode::Close,
+            0x9 => Opcode::Ping,
+            0xA => Opcode::Pong,
+            _ => return None,
+        })
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+struct Frame {
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
 
-    // Devserver always sends text messages. The combination of bitflags and opcode produces '129'
-    stream.write_all(&[129])?;
-    let mut second_byte: u8 = 0;
+// Encodes a single unfragmented frame as described in RFC 6455 section 5.2.
+// Frames sent by a server are never masked.
+fn write_frame<T: Write>(mut stream: T, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(payload.len() + 10);
+    bytes.push(0x80 | opcode.as_u8());
+
+    let length = payload.len();
+    if length < 126 {
+        bytes.push(length as u8);
+    } else if let Ok(length) = u16::try_from(length) {
+        bytes.push(126);
+        bytes.extend_from_slice(&length.to_be_bytes());
+    } else {
+        bytes.push(127);
+        bytes.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+
+    bytes.extend_from_slice(payload);
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+// Attempts to decode one frame from the start of `bytes`.
+// Returns the frame and the number of bytes it used, `None` if more bytes are needed, or the
+// close status code to fail the connection with if the client broke the protocol.
+fn read_frame(bytes: &[u8]) -> Result<Option<(Frame, usize)>, u16> {
+    if bytes.len() < 2 {
+        return Ok(None);
+    }
 
-    second_byte |= payload_length as u8;
-    stream.write_all(&[second_byte])?;
+    let fin = bytes[0] & 0x80 != 0;
+    let reserved = bytes[0] & 0x70;
+    let opcode = Opcode::from_u8(bytes[0] & 0x0F).ok_or(CLOSE_PROTOCOL_ERROR)?;
+    let masked = bytes[1] & 0x80 != 0;
 
-    if let Some(message) = message {
-        stream.write_all(message.as_bytes())?;
+    // No extensions are negotiated so the reserved bits must be clear,
+    // and every frame sent by a client must be masked.
+    if reserved != 0 || !masked {
+        return Err(CLOSE_PROTOCOL_ERROR);
     }
 
+    let mut offset = 2;
+    let length = match bytes[1] & 0x7F {
+        126 => {
+            let Some(length) = bytes.get(2..4) else {
+                return Ok(None);
+            };
+            offset += 2;
+            u16::from_be_bytes([length[0], length[1]]) as u64
+        }
+        127 => {
+            let Some(length) = bytes.get(2..10) else {
+                return Ok(None);
+            };
+            offset += 8;
+            u64::from_be_bytes(length.try_into().unwrap())
+        }
+        length => length as u64,
+    };
+
+    // Control frames can't be fragmented and their payload must fit in the first length byte.
+    if opcode.is_control() && (!fin || length > 125) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    if length > MAX_CLIENT_PAYLOAD {
+        return Err(CLOSE_TOO_BIG);
+    }
+
+    let Some(mask) = bytes.get(offset..offset + 4) else {
+        return Ok(None);
+    };
+    offset += 4;
+
+    let end = offset + length as usize;
+    let Some(payload) = bytes.get(offset..end) else {
+        return Ok(None);
+    };
+    let payload = payload
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { opcode, payload }, end)))
+}
+
+// Reads whatever is available into `buffer`, returning false once the client has hung up.
+// Reads time out after `POLL_INTERVAL` so the caller can do other work in between.
+fn fill_buffer<T: Read>(mut stream: T, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    let mut chunk = [0; 1024];
+    match stream.read(&mut chunk) {
+        Ok(0) => Ok(false),
+        Ok(read) => {
+            buffer.extend_from_slice(&chunk[..read]);
+            Ok(true)
+        }
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+// Starts the closing handshake and waits for the client to acknowledge it.
+fn close_connection<T: Read + Write>(
+    mut stream: T,
+    mut buffer: Vec<u8>,
+    code: u16,
+) -> io::Result<()> {
+    write_frame(&mut stream, Opcode::Close, &code.to_be_bytes())?;
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    while Instant::now() < deadline && fill_buffer(&mut stream, &mut buffer)? {
+        while let Ok(Some((frame, length))) = read_frame(&buffer) {
+            if frame.opcode == Opcode::Close {
+                return Ok(());
+            }
+            buffer.drain(..length);
+        }
+    }
     Ok(())
 }
 
-fn handle_websocket_handshake<T: Read + Write>(mut stream: T) {
-    let header = crate::read_header(&mut stream);
-    let response = parse_websocket_handshake(&header);
-    stream.write_all(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+/// Version of the JSON messages sent to reload clients.
+/// Bumped whenever an existing message changes in a way clients would notice.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// A message sent to reload clients.
+pub(crate) enum Message {
+    /// Files in the served folder changed, given by their URL paths.
+    /// An empty list means the page should be reloaded regardless.
+    Reload(Vec<String>),
+    /// Actions started running in response to a change.
+    BuildStart,
+    /// Every action succeeded. A `Reload` follows.
+    BuildOk,
+    /// An action failed and the error should be shown instead of the page.
+    BuildError(String),
+}
+
+impl Message {
+    fn encode(&self) -> String {
+        let (kind, fields) = match self {
+            Message::Reload(paths) => (
+                "reload",
+                format!(
+                    ",\"paths\":{}",
+                    json::string_array(paths.iter().map(String::as_str))
+                ),
+            ),
+            Message::BuildStart => ("build-start", String::new()),
+            Message::BuildOk => ("build-ok", String::new()),
+            Message::BuildError(error) => (
+                "build-error",
+                format!(",\"message\":{}", json::string(error)),
+            ),
+        };
+        format!("{{\"version\":{PROTOCOL_VERSION},\"type\":\"{kind}\"{fields}}}")
+    }
+}
+
+/// Messages waiting to be sent to every connected client.
+#[derive(Default)]
+pub(crate) struct Hub {
+    state: Mutex<HubState>,
+}
+
+#[derive(Default)]
+struct HubState {
+    // Total number of messages ever published.
+    published: u64,
+    recent: VecDeque<String>,
 }
 
-pub fn watch_for_reloads(address: IpAddr, path: &Path, actions: Vec<crate::Action>) {
-    // Setup websocket receiver.
-    let listener = TcpListener::bind((address, RELOAD_PORT)).unwrap();
+impl Hub {
+    /// Queue a message for every connected client.
+    pub(crate) fn publish(&self, message: Message) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.published += 1;
+        state.recent.push_back(message.encode());
+        if state.recent.len() > BACKLOG {
+            state.recent.pop_front();
+        }
+    }
 
-    let (tx, rx) = mpsc::channel();
-    let pair = Arc::new((Mutex::<Option<String>>::new(None), Condvar::new()));
+    fn published(&self) -> u64 {
+        self.state.lock().expect("Poisoned lock").published
+    }
 
-    // Is a 10ms delay here too short?
-    let watcher_config = Config::default().with_poll_interval(Duration::from_secs(10));
-    let mut watcher: RecommendedWatcher = Watcher::new(tx, watcher_config).unwrap();
-    watcher.watch(path, RecursiveMode::Recursive).unwrap();
+    // Messages published after the first `seen` messages. Updates `seen` to include them.
+    fn since(&self, seen: &mut u64) -> Vec<String> {
+        let state = self.state.lock().expect("Poisoned lock");
+        let unseen = (state.published - *seen).min(state.recent.len() as u64) as usize;
+        *seen = state.published;
+        state
+            .recent
+            .iter()
+            .skip(state.recent.len() - unseen)
+            .cloned()
+            .collect()
+    }
+}
 
-    let pair2 = pair.clone();
-    thread::spawn(move || {
-        let pair = pair2;
-        while let Ok(event) = rx.recv() {
-            match event {
-                Ok(event) => {
-                    if matches!(event.kind, EventKind::Modify(ModifyKind::Data(..))) {
-                        let mut m = pair.0.lock().expect("Poisoned lock");
+// Relays messages from the hub to a client and answers its control frames until it goes away.
+fn serve_websocket<T: Connection>(mut stream: T, hub: &Hub) -> io::Result<()> {
+    stream.socket().set_read_timeout(Some(POLL_INTERVAL))?;
 
-                        *m = None;
-                        for action in &actions {
-                            if let Err(err) = action() {
-                                *m = Some(format!("{err}"));
-                                break;
-                            }
-                        }
+    let mut seen = hub.published();
+    let mut buffer = Vec::new();
+    let mut last_activity = Instant::now();
+    let mut ping_sent: Option<Instant> = None;
 
-                        pair.1.notify_all();
+    while fill_buffer(&mut stream, &mut buffer)? {
+        loop {
+            let (frame, length) = match read_frame(&buffer) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(code) => return close_connection(&mut stream, buffer, code),
+            };
+            buffer.drain(..length);
+            last_activity = Instant::now();
 
-                        // Flush
-                        while rx.try_recv().is_ok() {}
-                    }
+            match frame.opcode {
+                Opcode::Ping => write_frame(&mut stream, Opcode::Pong, &frame.payload)?,
+                Opcode::Pong => ping_sent = None,
+                Opcode::Close => {
+                    // Echo the status code back to complete the handshake, then hang up.
+                    let code = match frame.payload.get(..2) {
+                        Some(code) => [code[0], code[1]],
+                        None => CLOSE_NORMAL.to_be_bytes(),
+                    };
+                    return write_frame(&mut stream, Opcode::Close, &code);
                 }
-                Err(e) => println!("File watch error: {:?}", e),
+                // The client has nothing to tell us, so data frames are ignored.
+                Opcode::Text | Opcode::Binary | Opcode::Continuation => {}
             }
         }
-        todo!()
-    });
-
-    // The only incoming message we expect to receive is the initial handshake.
-    for stream in listener.incoming() {
-        let pair = pair.clone();
-        thread::spawn(move || {
-            if let Ok(mut stream) = stream {
-                handle_websocket_handshake(&mut stream);
-
-                // Watch for file changes until the socket closes.
-                loop {
-                    let m = pair.1.wait(pair.0.lock().expect("poisoned lock")).unwrap();
-                    // A blank message is sent triggering a refresh on any file change.
-                    // If this message fails to send, then likely the socket has been closed.
-                    if send_websocket_message(&stream, m.clone()).is_err() {
-                        break;
-                    };
-                }
+
+        for message in hub.since(&mut seen) {
+            write_frame(&mut stream, Opcode::Text, message.as_bytes())?;
+        }
+
+        match ping_sent {
+            Some(sent) if sent.elapsed() > REPLY_TIMEOUT => {
+                // The client is gone without saying goodbye.
+                return Ok(());
             }
-        });
+            None if last_activity.elapsed() > PING_INTERVAL => {
+                write_frame(&mut stream, Opcode::Ping, &[])?;
+                ping_sent = Some(Instant::now());
+                last_activity = Instant::now();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Complete the websocket handshake for a request to `RELOAD_PATH`
+/// and keep the connection open to deliver reload messages.
+pub(crate) fn accept<T: Connection>(mut stream: T, request: &Request, hub: &Hub) {
+    let key = request.header("Sec-WebSocket-Key");
+    let is_upgrade = request.method == "GET" && request.header_contains("Upgrade", "websocket");
+    let Some(key) = key.filter(|_| is_upgrade) else {
+        let response = "HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\n\r\n";
+        let _ = stream
+            .write_all(response.as_bytes())
+            .and_then(|_| stream.flush());
+        return;
+    };
+
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", websocket_accept_key(key));
+    let result = stream
+        .write_all(response.as_bytes())
+        .and_then(|_| stream.flush())
+        // Watch for file changes until the socket closes.
+        .and_then(|_| serve_websocket(stream, hub));
+    if let Err(err) = result {
+        println!("Reload connection error: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes a frame the way a client has to, masked with a fixed key.
+    fn client_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, opcode, payload).unwrap();
+        let header_length = frame.len() - payload.len();
+        let mask = [0x12, 0x34, 0x56, 0x78];
+
+        let mut masked = frame[..header_length].to_vec();
+        masked[1] |= 0x80;
+        masked.extend_from_slice(&mask);
+        masked.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        masked
+    }
+
+    #[test]
+    fn lengths_use_the_smallest_encoding() {
+        for (length, header) in [
+            (0, vec![0x81, 0]),
+            (125, vec![0x81, 125]),
+            (126, vec![0x81, 126, 0, 126]),
+            (65535, vec![0x81, 126, 0xFF, 0xFF]),
+            (65536, vec![0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ] {
+            let payload = vec![b'a'; length];
+            let mut frame = Vec::new();
+            write_frame(&mut frame, Opcode::Text, &payload).unwrap();
+            assert_eq!(frame[..header.len()], header[..], "length {length}");
+            assert_eq!(frame.len(), header.len() + length);
+        }
+    }
+
+    #[test]
+    fn masked_client_frames_are_decoded() {
+        for length in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let bytes = client_frame(Opcode::Binary, &payload);
+            let (frame, used) = read_frame(&bytes).unwrap().unwrap();
+            assert_eq!(frame.opcode, Opcode::Binary);
+            assert_eq!(frame.payload, payload, "length {length}");
+            assert_eq!(used, bytes.len());
+        }
+    }
+
+    #[test]
+    fn incomplete_frames_wait_for_more() {
+        let bytes = client_frame(Opcode::Text, &[b'a'; 300]);
+        for end in [0, 1, 2, 3, 4, 7, bytes.len() - 1] {
+            assert!(read_frame(&bytes[..end]).unwrap().is_none(), "{end} bytes");
+        }
+
+        // Whatever follows a frame is left for the next one.
+        let mut two = client_frame(Opcode::Ping, b"1");
+        let first = two.len();
+        two.extend(client_frame(Opcode::Pong, b"2"));
+        let (frame, used) = read_frame(&two).unwrap().unwrap();
+        assert_eq!((frame.opcode, used), (Opcode::Ping, first));
+    }
+
+    #[test]
+    fn unmasked_client_frames_are_rejected() {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, Opcode::Text, b"hello").unwrap();
+        assert_eq!(read_frame(&frame).err(), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn oversized_client_frames_are_rejected() {
+        let payload = vec![0; MAX_CLIENT_PAYLOAD as usize + 1];
+        let frame = client_frame(Opcode::Binary, &payload);
+        // The header alone is enough to tell.
+        assert_eq!(read_frame(&frame[..10]).err(), Some(CLOSE_TOO_BIG));
+
+        let frame = [0x82, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(read_frame(&frame).err(), Some(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn broken_control_frames_are_rejected() {
+        let frame = client_frame(Opcode::Ping, &[0; 126]);
+        assert_eq!(read_frame(&frame).err(), Some(CLOSE_PROTOCOL_ERROR));
+
+        let mut fragmented = client_frame(Opcode::Close, &CLOSE_NORMAL.to_be_bytes());
+        fragmented[0] &= 0x7F;
+        assert_eq!(read_frame(&fragmented).err(), Some(CLOSE_PROTOCOL_ERROR));
+
+        let mut reserved = client_frame(Opcode::Text, b"hello");
+        reserved[0] |= 0x40;
+        assert_eq!(read_frame(&reserved).err(), Some(CLOSE_PROTOCOL_ERROR));
     }
 }
@@ -0,0 +1,114 @@
+//! Parsing of the request line and headers sent by a client.
+
+use std::str;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// The request target as sent, including any query string.
+    pub(crate) target: String,
+    /// Like `HTTP/1.1`.
+    pub(crate) version: String,
+    pub(crate) headers: Headers,
+}
+
+impl Request {
+    /// Parse a request header as returned by `read_header`.
+    /// Returns `None` if the request is malformed.
+    pub(crate) fn parse(bytes: &[u8]) -> Option<Self> {
+        let request_string = str::from_utf8(bytes).ok()?;
+        let mut lines = request_string.split("\r\n");
+
+        // Split the request line into different parts.
+        let mut parts = lines.next()?.split(' ');
+        let method = parts.next()?.trim().to_owned();
+        let target = parts.next()?.trim().to_owned();
+        let version = parts.next()?.trim().to_owned();
+        // `*` asks about the server as a whole rather than a file.
+        let is_server_wide = method == "OPTIONS" && target == "*";
+        if method.is_empty() || !(target.starts_with('/') || is_server_wide) {
+            return None;
+        }
+
+        let headers = Headers::parse(lines)?;
+
+        Some(Self {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+
+    /// The path portion of the target, without parameters.
+    pub(crate) fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// Whether the client wants to keep the connection open for more requests.
+    /// Older clients have to ask for it, which isn't supported.
+    pub(crate) fn is_persistent(&self) -> bool {
+        self.version == "HTTP/1.1" && !self.header_contains("Connection", "close")
+    }
+
+    /// Look up a parameter in the query string, such as `format` in `?format=json`.
+    pub(crate) fn query(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.target.split_once('?')?;
+        query
+            .split('&')
+            .find_map(|pair| match pair.split_once('=') {
+                Some((key, value)) if key == name => Some(value),
+                None if pair == name => Some(""),
+                _ => None,
+            })
+    }
+
+    /// Look up a header by its case-insensitive name.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Check if a comma separated header such as `Connection` contains `token`.
+    pub(crate) fn header_contains(&self, name: &str, token: &str) -> bool {
+        self.headers.contains(name, token)
+    }
+}
+
+/// Header fields in the order they were sent.
+pub(crate) struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Parse header lines, stopping at the empty line that ends them.
+    pub(crate) fn parse<'a>(lines: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let mut headers = Vec::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':')?;
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+        Some(Self(headers))
+    }
+
+    /// Look up a header by its case-insensitive name.
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Check if a comma separated header such as `Connection` contains `token`.
+    pub(crate) fn contains(&self, name: &str, token: &str) -> bool {
+        self.get(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
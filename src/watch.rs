@@ -0,0 +1,561 @@
+//! Watching for changes and running actions in response to them.
+
+use crate::{
+    changes::{Changes, WatchEvent},
+    filter::Filter,
+    reload::{Hub, Message},
+    Action, WatchOptions,
+};
+use globset::{GlobBuilder, GlobMatcher};
+use notify::{event::ModifyKind, Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Upper bound on how long a stream of events can hold back a rebuild.
+const MAX_DEBOUNCE_WAIT: Duration = Duration::from_secs(2);
+
+// The part of the file system a rule applies to.
+enum Scope {
+    // Everything inside a folder.
+    Folder(PathBuf),
+    // A single file. Its folder is watched so that replacing the file is noticed.
+    File(PathBuf),
+    // Files inside a folder matching a glob relative to it.
+    Glob(PathBuf, GlobMatcher),
+}
+
+impl Scope {
+    fn new(path: &Path) -> Result<Self, globset::Error> {
+        let is_pattern = |component: &Component| {
+            let component = component.as_os_str().to_string_lossy();
+            component.contains(['*', '?', '[', '{'])
+        };
+
+        let Some(index) = path.components().position(|c| is_pattern(&c)) else {
+            let path = resolve(path);
+            return Ok(if path.is_file() {
+                Scope::File(path)
+            } else {
+                Scope::Folder(path)
+            });
+        };
+
+        let folder: PathBuf = path.components().take(index).collect();
+        let pattern: PathBuf = path.components().skip(index).collect();
+        let glob = GlobBuilder::new(&pattern.to_string_lossy())
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        Ok(Scope::Glob(resolve(&folder), glob))
+    }
+
+    // The folder to watch, if everything inside it should be watched too.
+    fn folder(&self) -> Option<&Path> {
+        match self {
+            Scope::Folder(folder) | Scope::Glob(folder, _) => Some(folder),
+            Scope::File(_) => None,
+        }
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        match self {
+            Scope::Folder(folder) => path.starts_with(folder),
+            Scope::File(file) => path == file,
+            Scope::Glob(folder, glob) => path
+                .strip_prefix(folder)
+                .is_ok_and(|relative| glob.is_match(relative)),
+        }
+    }
+}
+
+// Converts a changed file into the URL it is served at, if it is inside the served folder.
+fn url_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut url = String::new();
+    for component in relative.components() {
+        url.push('/');
+        url.push_str(&component.as_os_str().to_str()?.replace(' ', "%20"));
+    }
+    Some(url)
+}
+
+// Events are reported relative to the watched path, so everything they're compared with has
+// to be resolved the same way. Paths that don't exist yet are resolved through their parent.
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => resolve(parent).join(name),
+        _ => path.to_owned(),
+    }
+}
+
+// The filter for the innermost watched folder containing `path`, if any.
+fn filter_for<'a>(filters: &'a mut [Filter], path: &Path) -> Option<&'a mut Filter> {
+    filters
+        .iter_mut()
+        .filter(|filter| path.starts_with(filter.root()))
+        .max_by_key(|filter| filter.root().components().count())
+}
+
+// Ignored folders aren't watched at all, so folders like `node_modules` don't use up the
+// system's limit on watches. That means watching each of the other folders separately.
+fn watch_folders(watcher: &mut RecommendedWatcher, filter: &mut Filter, path: &Path) {
+    let mut pending = vec![path.to_owned()];
+    while let Some(folder) = pending.pop() {
+        if filter.is_ignored(&folder, true) {
+            continue;
+        }
+        filter.load_ignore_files(&folder);
+
+        // Watch before listing the folder so nothing created in between is missed.
+        if let Err(e) = watcher.watch(&folder, RecursiveMode::NonRecursive) {
+            println!("Could not watch [ {} ]: {:?}", folder.display(), e);
+            continue;
+        }
+        if let Ok(entries) = fs::read_dir(&folder) {
+            pending.extend(
+                entries
+                    .flatten()
+                    .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+                    .map(|entry| entry.path()),
+            );
+        }
+    }
+}
+
+/// Lets an action know that newer changes have made its work obsolete, so it should stop early.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+enum Signal {
+    Event(notify::Result<notify::Event>),
+    // A build finished, with the error if it failed.
+    Finished(Result<(), String>),
+}
+
+// Actions to run, with the changes to pass them, by the index of their rule.
+struct Job {
+    rules: Vec<(usize, Changes)>,
+    cancel: CancelToken,
+}
+
+// Runs builds one at a time on their own thread, so changes can still be noticed while they run.
+fn run_builds(actions: Vec<Vec<Action>>, jobs: mpsc::Receiver<Job>, signals: mpsc::Sender<Signal>) {
+    for job in jobs {
+        let mut result = Ok(());
+        'rules: for (rule, changes) in &job.rules {
+            for action in &actions[*rule] {
+                if job.cancel.is_cancelled() {
+                    break 'rules;
+                }
+                if let Err(err) = action(changes, &job.cancel) {
+                    result = Err(format!("{err}"));
+                    break 'rules;
+                }
+            }
+        }
+        if signals.send(Signal::Finished(result)).is_err() {
+            return;
+        }
+    }
+}
+
+struct Running {
+    cancel: CancelToken,
+    // Everything that changed since the build started, including what it wrote itself.
+    changes: Changes,
+    // The files that changed while it ran, whoever wrote them.
+    written: BTreeSet<PathBuf>,
+}
+
+// What is known about which files are edited and which a build writes, since file events
+// don't tell which process made them.
+#[derive(Default)]
+struct Origins {
+    outputs: Vec<PathBuf>,
+    // Files whose changes started a build while none was running, so they're known to be edited.
+    sources: BTreeSet<PathBuf>,
+    // Files that changed while the last build ran, so a source the build rewrites itself, the
+    // way a formatter does, stops counting as edited after the first time.
+    written: BTreeSet<PathBuf>,
+}
+
+impl Origins {
+    fn is_output(&self, path: &Path) -> bool {
+        self.outputs.iter().any(|output| path.starts_with(output))
+    }
+
+    // Changes made while no build runs can only be edits.
+    fn edited<'a>(&mut self, paths: impl IntoIterator<Item = &'a Path>) {
+        for path in paths {
+            self.sources.insert(path.to_owned());
+            self.written.remove(path);
+        }
+    }
+
+    // Whether a change made while a build runs is an edit that needs building, rather than
+    // something the build wrote. Cancelling a build for its own output would start it over
+    // forever, so without declared outputs only files edited before count. Anything else,
+    // such as output that gets a new name every run, only reloads the page.
+    fn is_edit(&self, path: &Path) -> bool {
+        !self.is_output(path)
+            && !self.written.contains(path)
+            && (!self.outputs.is_empty() || self.sources.contains(path))
+    }
+}
+
+struct Watch<'a> {
+    root: PathBuf,
+    scopes: Vec<Scope>,
+    // Whether each rule has any actions.
+    has_actions: Vec<bool>,
+    events: Vec<WatchEvent>,
+    origins: Origins,
+    quiet_period: Duration,
+    watcher: RecommendedWatcher,
+    filters: Vec<Filter>,
+    hub: &'a Hub,
+    jobs: mpsc::Sender<Job>,
+    running: Option<Running>,
+    // Changes to build once the cancelled build has stopped.
+    restart: Option<Changes>,
+}
+
+impl Watch<'_> {
+    fn record(&mut self, changes: &mut Changes, event: notify::Result<notify::Event>) {
+        let mut event = match event {
+            Ok(event) => event,
+            Err(e) => return println!("File watch error: {:?}", e),
+        };
+
+        event.paths.retain(|path| {
+            let is_ignored = filter_for(&mut self.filters, path)
+                .is_some_and(|filter| filter.is_ignored(path, path.is_dir()));
+            // Only the named file is wanted from the folders watched for single file rules.
+            !is_ignored && self.scopes.iter().any(|scope| scope.contains(path))
+        });
+        for path in &event.paths {
+            let Some(filter) = filter_for(&mut self.filters, path) else {
+                continue;
+            };
+            if filter.is_ignore_file(path) {
+                filter.load_ignore_files(path.parent().unwrap_or(&self.root));
+            }
+            // Folders created or moved into the watched folder need watching as well.
+            let is_new = matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+            );
+            if is_new && path.is_dir() {
+                watch_folders(&mut self.watcher, filter, path);
+            }
+        }
+
+        changes.record(&event, &self.events);
+    }
+
+    // Records events until none arrive for the quiet period, so a burst of writes is handled
+    // at once. Stops early if a build finishes, since that has to be handled next.
+    fn debounce(
+        &mut self,
+        rx: &mpsc::Receiver<Signal>,
+        changes: &mut Changes,
+    ) -> Option<Result<(), String>> {
+        let deadline = Instant::now() + MAX_DEBOUNCE_WAIT;
+        while let Ok(signal) = rx.recv_timeout(self.quiet_period) {
+            match signal {
+                Signal::Event(event) => self.record(changes, event),
+                Signal::Finished(result) => return Some(result),
+            }
+            if Instant::now() > deadline {
+                break;
+            }
+        }
+        None
+    }
+
+    // The rules that have to run for `changes`, each with the changes that concern it.
+    // Changes to declared outputs only need a reload, rebuilding would just write them again.
+    fn plan(&self, changes: &Changes) -> Vec<(usize, Changes)> {
+        self.scopes
+            .iter()
+            .enumerate()
+            .filter(|(rule, _)| self.has_actions[*rule])
+            .map(|(rule, scope)| {
+                let changes =
+                    changes.filter(|path| scope.contains(path) && !self.origins.is_output(path));
+                (rule, changes)
+            })
+            .filter(|(_, changes)| !changes.is_empty())
+            .collect()
+    }
+
+    // The changes made while a build ran that need building.
+    fn edits(&self, changes: &Changes) -> Changes {
+        let changes = changes.filter(|path| self.origins.is_edit(path));
+        let mut edits = Changes::default();
+        for (_, rule_changes) in self.plan(&changes) {
+            edits.merge(rule_changes);
+        }
+        edits
+    }
+
+    fn start(&mut self, changes: Changes) {
+        let rules = self.plan(&changes);
+        if rules.is_empty() {
+            self.reload(&changes);
+            return;
+        }
+
+        self.hub.publish(Message::BuildStart);
+        let cancel = CancelToken::default();
+        let job = Job {
+            rules,
+            cancel: cancel.clone(),
+        };
+        if self.jobs.send(job).is_ok() {
+            self.running = Some(Running {
+                cancel,
+                changes,
+                written: BTreeSet::new(),
+            });
+        }
+    }
+
+    fn changed(&mut self, changes: Changes) {
+        if self.running.is_none() {
+            self.origins.edited(changes.iter().map(|(path, _)| path));
+            return self.start(changes);
+        }
+
+        // An edit makes the running build obsolete, so it's cancelled and started again with
+        // everything that changed. Anything else is taken to be the build's output, which is
+        // included in the reload but mustn't start another build.
+        let edits = self.edits(&changes);
+        let Some(running) = &mut self.running else {
+            return;
+        };
+        running
+            .written
+            .extend(changes.iter().map(|(path, _)| path.to_owned()));
+        if let Some(restart) = &mut self.restart {
+            restart.merge(changes);
+        } else if !edits.is_empty() {
+            running.cancel.cancel();
+            let mut restart = running.changes.clone();
+            restart.merge(changes);
+            self.restart = Some(restart);
+        } else {
+            running.changes.merge(changes);
+        }
+    }
+
+    fn finished(&mut self, rx: &mpsc::Receiver<Signal>, result: Result<(), String>) {
+        let Some(mut running) = self.running.take() else {
+            return;
+        };
+        if let Some(changes) = self.restart.take() {
+            self.origins.written = running.written;
+            return self.start(changes);
+        }
+
+        // Anything still being written, such as the last of the build's output, is part of this
+        // build. Edits among it still need building though.
+        let mut late = Changes::default();
+        self.debounce(rx, &mut late);
+        let edits = self.edits(&late);
+        running
+            .written
+            .extend(late.iter().map(|(path, _)| path.to_owned()));
+        running.changes.merge(late);
+        self.origins.written = running.written;
+
+        match result {
+            Ok(()) => {
+                self.hub.publish(Message::BuildOk);
+                self.reload(&running.changes);
+            }
+            Err(error) => self.hub.publish(Message::BuildError(error)),
+        }
+        if !edits.is_empty() {
+            self.start(edits);
+        }
+    }
+
+    fn reload(&self, changes: &Changes) {
+        // If anything changed outside the served folder we can't tell what the
+        // page depends on, so an empty list asks for a full reload.
+        let urls: Option<Vec<_>> = changes
+            .iter()
+            .map(|(path, _)| url_path(&self.root, path))
+            .collect();
+        self.hub.publish(Message::Reload(urls.unwrap_or_default()));
+    }
+}
+
+pub(crate) fn watch_for_reloads(root: &Path, watch: WatchOptions, hub: &Hub) {
+    let WatchOptions {
+        rules,
+        events,
+        debounce: quiet_period,
+        ignore,
+        ignore_files,
+        outputs,
+    } = watch;
+
+    let mut scopes = Vec::new();
+    let mut actions = Vec::new();
+    for rule in rules {
+        match Scope::new(&rule.path) {
+            Ok(scope) => {
+                scopes.push(scope);
+                actions.push(rule.actions);
+            }
+            Err(err) => println!(
+                "WARNING: Invalid watch pattern [ {} ]: {err}",
+                rule.path.display()
+            ),
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let (jobs, job_receiver) = mpsc::channel();
+
+    // Is a 10ms delay here too short?
+    let watcher_config = Config::default().with_poll_interval(Duration::from_secs(10));
+    let events_tx = tx.clone();
+    let handler = move |event| {
+        let _ = events_tx.send(Signal::Event(event));
+    };
+    let mut watcher: RecommendedWatcher = Watcher::new(handler, watcher_config).unwrap();
+
+    // Each watched folder gets its own filter since ignore patterns are relative to it.
+    let mut filters = Vec::new();
+    for scope in &scopes {
+        match scope {
+            Scope::File(file) => {
+                let folder = file.parent().unwrap_or(file);
+                if let Err(e) = watcher.watch(folder, RecursiveMode::NonRecursive) {
+                    println!("Could not watch [ {} ]: {:?}", folder.display(), e);
+                }
+            }
+            scope => {
+                let folder = scope.folder().unwrap();
+                let mut filter = Filter::new(folder, &ignore, ignore_files);
+                watch_folders(&mut watcher, &mut filter, folder);
+                filters.push(filter);
+            }
+        }
+    }
+
+    let mut watch = Watch {
+        root: resolve(root),
+        has_actions: actions.iter().map(|actions| !actions.is_empty()).collect(),
+        scopes,
+        events,
+        origins: Origins {
+            outputs: outputs.iter().map(|output| resolve(output)).collect(),
+            ..Origins::default()
+        },
+        quiet_period,
+        watcher,
+        filters,
+        hub,
+        jobs,
+        running: None,
+        restart: None,
+    };
+
+    thread::spawn(move || run_builds(actions, job_receiver, tx));
+
+    while let Ok(signal) = rx.recv() {
+        match signal {
+            Signal::Event(event) => {
+                let mut changes = Changes::default();
+                watch.record(&mut changes, event);
+                let finished = watch.debounce(&rx, &mut changes);
+
+                // Ignored events, or a temporary file that was created and removed again.
+                if !changes.is_empty() {
+                    watch.changed(changes);
+                }
+                if let Some(result) = finished {
+                    watch.finished(&rx, result);
+                }
+            }
+            Signal::Finished(result) => watch.finished(&rx, result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a build that writes `written`, the way `Watch::finished` records it.
+    fn build(origins: &mut Origins, written: &[&str]) {
+        origins.written = written.iter().map(PathBuf::from).collect();
+    }
+
+    #[test]
+    fn output_with_a_new_name_every_run_is_never_an_edit() {
+        let mut origins = Origins::default();
+        origins.edited([Path::new("src/app.js")]);
+
+        for run in 0..3 {
+            let stamp = format!("stamp-{run}");
+            assert!(!origins.is_edit(Path::new(&stamp)));
+            build(&mut origins, &[&stamp, "log"]);
+            assert!(!origins.is_edit(Path::new("log")));
+        }
+        assert!(origins.is_edit(Path::new("src/app.js")));
+    }
+
+    #[test]
+    fn sources_the_build_rewrites_stop_being_edits() {
+        let mut origins = Origins::default();
+        origins.edited([Path::new("src/app.js")]);
+        assert!(origins.is_edit(Path::new("src/app.js")));
+
+        build(&mut origins, &["src/app.js"]);
+        assert!(!origins.is_edit(Path::new("src/app.js")));
+
+        // Saving it while nothing builds makes it an edit again.
+        origins.edited([Path::new("src/app.js")]);
+        assert!(origins.is_edit(Path::new("src/app.js")));
+    }
+
+    #[test]
+    fn declared_outputs_make_anything_else_an_edit() {
+        let mut origins = Origins {
+            outputs: vec![PathBuf::from("dist")],
+            ..Origins::default()
+        };
+        assert!(origins.is_edit(Path::new("src/new.js")));
+        assert!(!origins.is_edit(Path::new("dist/app.js")));
+
+        build(&mut origins, &["dist/app.js", "src/app.js"]);
+        assert!(!origins.is_edit(Path::new("src/app.js")));
+    }
+}